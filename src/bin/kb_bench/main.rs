@@ -0,0 +1,497 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// kb_bench is a pgbench-like tool. It speaks the simple query protocol only,
+// so it works against both KuiBaDB and PostgreSQL.
+use anyhow::{anyhow, bail};
+use clap::{App, Arg};
+use std::fmt::Write as FmtWrite;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const OPT_HOST: &str = "host";
+const OPT_PORT: &str = "port";
+const OPT_USER: &str = "user";
+const OPT_DB: &str = "dbname";
+const OPT_INIT: &str = "initialize";
+const OPT_SCALE: &str = "scale";
+const OPT_CLIENTS: &str = "client";
+const OPT_TRANSACTIONS: &str = "transactions";
+const OPT_TIME: &str = "time";
+const OPT_BUILTIN: &str = "builtin";
+
+const NBRANCHES: u64 = 1;
+const NTELLERS: u64 = 10;
+const NACCOUNTS: u64 = 100000;
+const NSALES: u64 = 100000;
+const NPRODUCTS: u64 = 1000;
+const NSTORES: u64 = 100;
+const NDATES: u64 = 365;
+const INSERT_BATCH: u64 = 1000;
+
+struct Conn {
+    r: BufReader<TcpStream>,
+    w: BufWriter<TcpStream>,
+}
+
+fn put_cstr(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+fn read_u32(r: &mut impl Read) -> anyhow::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+// Extract the 'M' field of ErrorResponse.
+fn errmsg(body: &[u8]) -> String {
+    for field in body.split(|&v| v == 0) {
+        if let Some((&b'M', msg)) = field.split_first() {
+            return String::from_utf8_lossy(msg).to_string();
+        }
+    }
+    return String::from_utf8_lossy(body).to_string();
+}
+
+impl Conn {
+    fn connect(host: &str, port: u16, user: &str, db: &str) -> anyhow::Result<Conn> {
+        let stream = TcpStream::connect((host, port))?;
+        stream.set_nodelay(true)?;
+        let mut conn = Conn {
+            r: BufReader::new(stream.try_clone()?),
+            w: BufWriter::new(stream),
+        };
+        let mut body = Vec::new();
+        body.extend_from_slice(&0x0003_0000u32.to_be_bytes());
+        put_cstr(&mut body, "user");
+        put_cstr(&mut body, user);
+        put_cstr(&mut body, "database");
+        put_cstr(&mut body, db);
+        body.push(0);
+        conn.w.write_all(&((body.len() + 4) as u32).to_be_bytes())?;
+        conn.w.write_all(&body)?;
+        conn.w.flush()?;
+        loop {
+            let (typ, body) = conn.read_message()?;
+            match typ {
+                b'R' => {
+                    if body.len() < 4 || body[..4] != [0, 0, 0, 0] {
+                        bail!("unsupported authentication method. body={:?}", body);
+                    }
+                }
+                b'E' => bail!("connect failed: {}", errmsg(&body)),
+                b'Z' => return Ok(conn),
+                _ => {}
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> anyhow::Result<(u8, Vec<u8>)> {
+        let mut typ = [0u8; 1];
+        self.r.read_exact(&mut typ)?;
+        let len = read_u32(&mut self.r)? as usize;
+        if len < 4 {
+            bail!("invalid message length. type={} len={}", typ[0], len);
+        }
+        let mut body = vec![0u8; len - 4];
+        self.r.read_exact(&mut body)?;
+        Ok((typ[0], body))
+    }
+
+    // Run the query and wait for ReadyForQuery, the result is discarded.
+    fn query(&mut self, sql: &str) -> anyhow::Result<()> {
+        self.w.write_all(&[b'Q'])?;
+        self.w
+            .write_all(&((sql.len() + 1 + 4) as u32).to_be_bytes())?;
+        self.w.write_all(sql.as_bytes())?;
+        self.w.write_all(&[0])?;
+        self.w.flush()?;
+        let mut err = None;
+        loop {
+            let (typ, body) = self.read_message()?;
+            match typ {
+                b'E' => err = Some(errmsg(&body)),
+                b'Z' => break,
+                _ => {}
+            }
+        }
+        match err {
+            None => Ok(()),
+            Some(msg) => Err(anyhow!("query failed. query={} err={}", sql, msg)),
+        }
+    }
+}
+
+// xorshift64*, good enough for generating workloads.
+struct Rand(u64);
+
+impl Rand {
+    fn new(seed: u64) -> Rand {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |v| v.as_nanos() as u64);
+        Rand((nanos ^ seed.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // [1, n]
+    fn between(&mut self, n: u64) -> u64 {
+        self.next() % n + 1
+    }
+}
+
+// Generate the VALUES item of the i-th row, i starts from 1.
+type RowGen = fn(u64, &mut Rand) -> String;
+
+fn gen_branch(i: u64, _: &mut Rand) -> String {
+    format!("({}, 0, '')", i)
+}
+
+fn gen_teller(i: u64, _: &mut Rand) -> String {
+    format!("({}, {}, 0, '')", i, (i - 1) / NTELLERS + 1)
+}
+
+fn gen_account(i: u64, _: &mut Rand) -> String {
+    format!("({}, {}, 0, '')", i, (i - 1) / NACCOUNTS + 1)
+}
+
+fn gen_product(i: u64, r: &mut Rand) -> String {
+    format!("({}, {}, {})", i, r.between(20), r.between(1000))
+}
+
+fn gen_store(i: u64, r: &mut Rand) -> String {
+    format!("({}, {})", i, r.between(10))
+}
+
+fn gen_date(i: u64, _: &mut Rand) -> String {
+    format!("({}, {}, 2021)", i, (i - 1) / 31 + 1)
+}
+
+fn gen_sale(_: u64, r: &mut Rand) -> String {
+    let qty = r.between(10);
+    format!(
+        "({}, {}, {}, {}, {})",
+        r.between(NDATES),
+        r.between(NPRODUCTS),
+        r.between(NSTORES),
+        qty,
+        qty * r.between(1000)
+    )
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Builtin {
+    TpcbLike,
+    Star,
+}
+
+impl Builtin {
+    fn parse(name: &str) -> anyhow::Result<Builtin> {
+        match name {
+            "tpcb-like" => Ok(Builtin::TpcbLike),
+            "star" => Ok(Builtin::Star),
+            _ => bail!("unknown builtin script. name={}", name),
+        }
+    }
+
+    fn schema(self) -> &'static [&'static str] {
+        match self {
+            Builtin::TpcbLike => &[
+                "drop table if exists kb_accounts, kb_branches, kb_tellers, kb_history",
+                "create table kb_branches(bid int not null, bbalance int, filler varchar)",
+                "create table kb_tellers(tid int not null, bid int, tbalance int, filler varchar)",
+                "create table kb_accounts(aid int not null, bid int, abalance int, filler varchar)",
+                "create table kb_history(tid int, bid int, aid int, delta int, mtime bigint, filler varchar)",
+            ],
+            Builtin::Star => &[
+                "drop table if exists kb_sales, kb_products, kb_stores, kb_dates",
+                "create table kb_products(product_id int not null, category int, price int)",
+                "create table kb_stores(store_id int not null, region int)",
+                "create table kb_dates(date_id int not null, month int, year int)",
+                "create table kb_sales(date_id int, product_id int, store_id int, qty int, amount bigint)",
+            ],
+        }
+    }
+
+    // (table, row count, row generator)
+    fn tables(self, scale: u64) -> Vec<(&'static str, u64, RowGen)> {
+        match self {
+            Builtin::TpcbLike => vec![
+                ("kb_branches", NBRANCHES * scale, gen_branch as RowGen),
+                ("kb_tellers", NTELLERS * scale, gen_teller as RowGen),
+                ("kb_accounts", NACCOUNTS * scale, gen_account as RowGen),
+            ],
+            Builtin::Star => vec![
+                ("kb_products", NPRODUCTS, gen_product as RowGen),
+                ("kb_stores", NSTORES, gen_store as RowGen),
+                ("kb_dates", NDATES, gen_date as RowGen),
+                ("kb_sales", NSALES * scale, gen_sale as RowGen),
+            ],
+        }
+    }
+
+    fn transaction(self, scale: u64, r: &mut Rand) -> Vec<String> {
+        match self {
+            Builtin::TpcbLike => {
+                let aid = r.between(NACCOUNTS * scale);
+                let bid = r.between(NBRANCHES * scale);
+                let tid = r.between(NTELLERS * scale);
+                let delta = r.between(10001) as i64 - 5001;
+                vec![
+                    "begin".to_string(),
+                    format!(
+                        "update kb_accounts set abalance = abalance + {} where aid = {}",
+                        delta, aid
+                    ),
+                    format!("select abalance from kb_accounts where aid = {}", aid),
+                    format!(
+                        "update kb_tellers set tbalance = tbalance + {} where tid = {}",
+                        delta, tid
+                    ),
+                    format!(
+                        "update kb_branches set bbalance = bbalance + {} where bid = {}",
+                        delta, bid
+                    ),
+                    format!(
+                        "insert into kb_history values ({}, {}, {}, {}, 0, '')",
+                        tid, bid, aid, delta
+                    ),
+                    "commit".to_string(),
+                ]
+            }
+            Builtin::Star => {
+                let query = match r.between(3) {
+                    1 => format!(
+                        "select p.category, sum(s.amount) from kb_sales s, kb_products p \
+                         where s.product_id = p.product_id and s.date_id <= {} group by p.category",
+                        r.between(NDATES)
+                    ),
+                    2 => format!(
+                        "select st.region, count(*), sum(s.qty) from kb_sales s, kb_stores st \
+                         where s.store_id = st.store_id and st.region = {} group by st.region",
+                        r.between(10)
+                    ),
+                    _ => format!(
+                        "select d.month, sum(s.amount) from kb_sales s, kb_dates d \
+                         where s.date_id = d.date_id and d.month = {} group by d.month",
+                        r.between(12)
+                    ),
+                };
+                vec![query]
+            }
+        }
+    }
+}
+
+struct Opts {
+    host: String,
+    port: u16,
+    user: String,
+    db: String,
+    scale: u64,
+    clients: usize,
+    transactions: u64,
+    duration: Option<Duration>,
+    builtin: Builtin,
+}
+
+impl Opts {
+    fn connect(&self) -> anyhow::Result<Conn> {
+        Conn::connect(&self.host, self.port, &self.user, &self.db)
+    }
+}
+
+fn initialize(opts: &Opts) -> anyhow::Result<()> {
+    let mut conn = opts.connect()?;
+    for ddl in opts.builtin.schema() {
+        conn.query(ddl)?;
+    }
+    let mut rand = Rand::new(0);
+    for (table, rows, gen) in opts.builtin.tables(opts.scale) {
+        let start = Instant::now();
+        let mut i = 1;
+        while i <= rows {
+            let end = std::cmp::min(i + INSERT_BATCH, rows + 1);
+            let mut sql = format!("insert into {} values ", table);
+            for rowid in i..end {
+                if rowid != i {
+                    sql.push(',');
+                }
+                sql.push_str(&gen(rowid, &mut rand));
+            }
+            conn.query(&sql)?;
+            i = end;
+        }
+        println!(
+            "load {} rows into {} in {:.3}s",
+            rows,
+            table,
+            start.elapsed().as_secs_f64()
+        );
+    }
+    return Ok(());
+}
+
+// Return the latency of every transaction.
+fn run_client(opts: &Opts, clientid: usize) -> anyhow::Result<Vec<Duration>> {
+    let mut conn = opts.connect()?;
+    let mut rand = Rand::new(clientid as u64 + 1);
+    let mut latencies = Vec::new();
+    let start = Instant::now();
+    loop {
+        match opts.duration {
+            Some(duration) if start.elapsed() >= duration => break,
+            None if latencies.len() as u64 >= opts.transactions => break,
+            _ => {}
+        }
+        let txnstart = Instant::now();
+        for sql in opts.builtin.transaction(opts.scale, &mut rand) {
+            conn.query(&sql)?;
+        }
+        latencies.push(txnstart.elapsed());
+    }
+    return Ok(latencies);
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let idx = ((sorted.len() as f64 * p).ceil() as usize).clamp(1, sorted.len()) - 1;
+    sorted[idx]
+}
+
+fn report(opts: &Opts, mut latencies: Vec<Duration>, elapsed: Duration) -> String {
+    latencies.sort();
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let total: Duration = latencies.iter().sum();
+    let avg = if latencies.is_empty() {
+        0.0
+    } else {
+        ms(total) / latencies.len() as f64
+    };
+    let mut out = String::new();
+    let _ = writeln!(&mut out, "builtin: {:?}", opts.builtin);
+    let _ = writeln!(&mut out, "scaling factor: {}", opts.scale);
+    let _ = writeln!(&mut out, "number of clients: {}", opts.clients);
+    let _ = writeln!(
+        &mut out,
+        "number of transactions actually processed: {}",
+        latencies.len()
+    );
+    let _ = writeln!(&mut out, "latency average = {:.3} ms", avg);
+    for &p in &[0.5, 0.9, 0.99] {
+        let _ = writeln!(
+            &mut out,
+            "latency p{} = {:.3} ms",
+            p * 100.0,
+            ms(percentile(&latencies, p))
+        );
+    }
+    let _ = writeln!(
+        &mut out,
+        "tps = {:.3}",
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    return out;
+}
+
+fn run(opts: &Opts) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let latencies = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..opts.clients)
+            .map(|clientid| s.spawn(move || run_client(opts, clientid)))
+            .collect();
+        let mut latencies = Vec::new();
+        for handle in handles {
+            match handle.join() {
+                Ok(Ok(v)) => latencies.extend(v),
+                Ok(Err(err)) => return Err(err),
+                Err(_) => bail!("client thread panicked"),
+            }
+        }
+        Ok(latencies)
+    })?;
+    print!("{}", report(opts, latencies, start.elapsed()));
+    return Ok(());
+}
+
+fn main() {
+    let cmdline = App::new("kb_bench")
+        .version(kuiba::KB_VERSTR)
+        .author("盏一 <w@hidva.com>")
+        .about("kb_bench is a benchmarking tool for KuiBaDB")
+        .arg(Arg::with_name(OPT_HOST).short("h").takes_value(true))
+        .arg(Arg::with_name(OPT_PORT).short("p").takes_value(true))
+        .arg(Arg::with_name(OPT_USER).short("U").takes_value(true))
+        .arg(Arg::with_name(OPT_DB).short("d").takes_value(true))
+        .arg(Arg::with_name(OPT_INIT).short("i"))
+        .arg(Arg::with_name(OPT_SCALE).short("s").takes_value(true))
+        .arg(Arg::with_name(OPT_CLIENTS).short("c").takes_value(true))
+        .arg(
+            Arg::with_name(OPT_TRANSACTIONS)
+                .short("t")
+                .takes_value(true)
+                .conflicts_with(OPT_TIME),
+        )
+        .arg(Arg::with_name(OPT_TIME).short("T").takes_value(true))
+        .arg(
+            Arg::with_name(OPT_BUILTIN)
+                .short("b")
+                .takes_value(true)
+                .possible_values(&["tpcb-like", "star"]),
+        )
+        .get_matches();
+
+    let opts = Opts {
+        host: cmdline
+            .value_of(OPT_HOST)
+            .unwrap_or("127.0.0.1")
+            .to_string(),
+        port: cmdline
+            .value_of(OPT_PORT)
+            .map_or(1218, |v| v.parse().unwrap()),
+        user: cmdline.value_of(OPT_USER).unwrap_or("kuiba").to_string(),
+        db: cmdline.value_of(OPT_DB).unwrap_or("kuiba").to_string(),
+        scale: cmdline
+            .value_of(OPT_SCALE)
+            .map_or(1, |v| v.parse().unwrap()),
+        clients: cmdline
+            .value_of(OPT_CLIENTS)
+            .map_or(1, |v| v.parse().unwrap()),
+        transactions: cmdline
+            .value_of(OPT_TRANSACTIONS)
+            .map_or(10, |v| v.parse().unwrap()),
+        duration: cmdline
+            .value_of(OPT_TIME)
+            .map(|v| Duration::from_secs(v.parse().unwrap())),
+        builtin: Builtin::parse(cmdline.value_of(OPT_BUILTIN).unwrap_or("tpcb-like")).unwrap(),
+    };
+    let ret = if cmdline.is_present(OPT_INIT) {
+        initialize(&opts)
+    } else {
+        run(&opts)
+    };
+    if let Err(err) = ret {
+        eprintln!("kb_bench: {:#}", err);
+        std::process::exit(1);
+    }
+    return;
+}
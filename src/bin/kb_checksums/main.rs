@@ -0,0 +1,176 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// kb_checksums verifies, enables or disables the data block checksums of a stopped KuiBaDB,
+// just like pg_checksums. The state is recorded in the data directory and picked up by
+// LocalSmgr at the next startup.
+use clap::{App, Arg};
+use kuiba::postmaster::lock_datadir;
+use kuiba::smgr::{
+    data_checksums_enabled, set_checksum, set_data_checksums, verify_checksum, BASE_DIR, BLCKSZ,
+    TBLSPC_DIR,
+};
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+const OPT_DATADIR: &str = "datadir";
+const OPT_ENABLE: &str = "enable";
+const OPT_DISABLE: &str = "disable";
+const OPT_VERIFY: &str = "verify";
+
+#[derive(Default)]
+struct Counters {
+    files: u64,
+    blocks: u64,
+    bad_blocks: u64,
+}
+
+fn scan_file(path: &Path, enable: bool, counters: &mut Counters) -> std::io::Result<()> {
+    let file = OpenOptions::new().read(true).write(enable).open(path)?;
+    let nblocks = file.metadata()?.len() / BLCKSZ as u64;
+    let mut buf = vec![0u8; BLCKSZ];
+    for blkno in 0..nblocks {
+        let off = blkno * BLCKSZ as u64;
+        file.read_exact_at(&mut buf, off)?;
+        let blkno = blkno as u32;
+        if enable {
+            set_checksum(&mut buf, blkno);
+            file.write_all_at(&buf, off)?;
+        } else if !verify_checksum(&buf, blkno) {
+            eprintln!(
+                "checksum verification failed. path={:?} blkno={}",
+                path, blkno
+            );
+            counters.bad_blocks += 1;
+        }
+    }
+    if enable {
+        file.sync_data()?;
+    }
+    counters.files += 1;
+    counters.blocks += nblocks;
+    return Ok(());
+}
+
+// spcdir is base or kb_tblspc/<spcoid>, which contains <db>/<rel> files.
+fn scan_spcdir(spcdir: &Path, enable: bool, counters: &mut Counters) -> std::io::Result<()> {
+    for dbdir in std::fs::read_dir(spcdir)? {
+        let dbdir = dbdir?.path();
+        if !dbdir.is_dir() {
+            continue;
+        }
+        for relfile in std::fs::read_dir(&dbdir)? {
+            let relfile = relfile?.path();
+            if relfile.is_file() {
+                scan_file(&relfile, enable, counters)?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+fn scan_datadir(datadir: &str, enable: bool) -> std::io::Result<Counters> {
+    let mut counters = Counters::default();
+    let datadir = Path::new(datadir);
+    // base/ is created by Smgr::create(), it does not exist in a fresh data directory.
+    let base_dir = datadir.join(BASE_DIR);
+    if base_dir.exists() {
+        scan_spcdir(&base_dir, enable, &mut counters)?;
+    }
+    let tblspc_dir = datadir.join(TBLSPC_DIR);
+    if tblspc_dir.exists() {
+        for spcdir in std::fs::read_dir(&tblspc_dir)? {
+            scan_spcdir(&spcdir?.path(), enable, &mut counters)?;
+        }
+    }
+    return Ok(counters);
+}
+
+fn exit_on_err<T>(res: anyhow::Result<T>, what: &str) -> T {
+    match res {
+        Ok(v) => v,
+        Err(err) => {
+            eprintln!("{} failed. err={:#}", what, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let cmdline = App::new("kb_checksums")
+        .version(kuiba::KB_VERSTR)
+        .author("盏一 <w@hidva.com>")
+        .about("kb_checksums verifies, enables or disables data checksums of a stopped KuiBaDB")
+        .arg(
+            Arg::with_name(OPT_DATADIR)
+                .short("D")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(OPT_VERIFY)
+                .short("c")
+                .long("verify")
+                .conflicts_with_all(&[OPT_ENABLE, OPT_DISABLE])
+                .help("Verify data checksums, this is the default mode"),
+        )
+        .arg(
+            Arg::with_name(OPT_ENABLE)
+                .short("e")
+                .long("enable")
+                .conflicts_with(OPT_DISABLE)
+                .help("Enable data checksums"),
+        )
+        .arg(
+            Arg::with_name(OPT_DISABLE)
+                .short("d")
+                .long("disable")
+                .help("Disable data checksums"),
+        )
+        .get_matches();
+    let datadir = cmdline.value_of(OPT_DATADIR).unwrap();
+    // The server reads the checksum state only at startup, so it must not be running, and it
+    // can not be started while we are running since we hold the pid file.
+    let _pidfile = exit_on_err(lock_datadir(Path::new(datadir)), "lock data directory");
+    if cmdline.is_present(OPT_DISABLE) {
+        exit_on_err(set_data_checksums(Path::new(datadir), false), "disable");
+        println!("Checksums disabled in cluster.");
+        return;
+    }
+    let enable = cmdline.is_present(OPT_ENABLE);
+    let enabled = exit_on_err(data_checksums_enabled(Path::new(datadir)), "read state");
+    if enable && enabled {
+        eprintln!("data checksums are already enabled in cluster");
+        std::process::exit(1);
+    }
+    if !enable && !enabled {
+        eprintln!("data checksums are not enabled in cluster");
+        std::process::exit(1);
+    }
+    let counters = exit_on_err(
+        scan_datadir(datadir, enable).map_err(anyhow::Error::from),
+        "scan data directory",
+    );
+    println!("Files scanned: {}", counters.files);
+    println!("Blocks scanned: {}", counters.blocks);
+    if enable {
+        // Only record the state after all blocks have been checksummed and synced.
+        exit_on_err(set_data_checksums(Path::new(datadir), true), "enable");
+        println!("Checksums enabled in cluster.");
+        return;
+    }
+    println!("Bad checksums: {}", counters.bad_blocks);
+    if counters.bad_blocks > 0 {
+        std::process::exit(1);
+    }
+    return;
+}
@@ -11,15 +11,17 @@
 
 use clap::{App, Arg};
 use kuiba::guc::{self, GucState};
+use kuiba::postmaster::{self, PmState, ShutdownMode};
 use kuiba::{postgres_main, GlobalState};
 use std::io;
 use std::net::TcpListener;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio;
 use tokio::runtime::{Builder, Runtime};
-use tracing::warn;
+use tracing::{info, warn};
 
 const OPT_DATADIR: &str = "datadir";
 const OPT_BUFFLOG_LINE_MAX: &str = "bufflog_line_max";
@@ -31,7 +33,9 @@ fn new_runtime(gucstate: &GucState) -> io::Result<Runtime> {
     let stack_size = guc::get_int(&gucstate, guc::TokioThreadStackSize) as usize;
     let threads = guc::get_int(&gucstate, guc::TokioWorkerThreads) as usize;
     let mut builder = Builder::new_multi_thread();
+    // statement_timeout relies on tokio::time.
     builder
+        .enable_time()
         .max_blocking_threads(max_blocking_threads)
         .thread_keep_alive(keep_alive)
         .thread_stack_size(stack_size);
@@ -46,6 +50,10 @@ async fn do_main(gucstate: GucState) {
     let port = guc::get_int(&gstate.gucstate, guc::Port) as u16;
     let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
     let listener = listener.as_raw_fd();
+    let pm = gstate.pm;
+    pm.set_listenfd(listener);
+    pm.start_signal_thread().unwrap();
+    pm.set_state(PmState::Running);
     let uring = gstate.urings.non_iopoll();
     loop {
         match uring.accept(listener).await {
@@ -54,10 +62,20 @@ async fn do_main(gucstate: GucState) {
                 tokio::spawn(postgres_main(gstate, srvfd, cliaddr));
             }
             Err(e) => {
+                if pm.shutdown_mode() != ShutdownMode::None {
+                    break;
+                }
                 warn!("accept failed. err={:#}", e);
             }
         }
     }
+    info!("stop accepting connections. mode={:?}", pm.shutdown_mode());
+    let stat = gstate.stat;
+    tokio::task::spawn_blocking(move || pm.wait_sessions(stat))
+        .await
+        .unwrap();
+    // TODO: write the shutdown checkpoint once we have the WAL.
+    info!("database system is shut down");
 }
 
 fn main() {
@@ -85,7 +103,11 @@ fn main() {
         .value_of(OPT_BUFFLOG_LINE_MAX)
         .map(|v| v.parse().unwrap())
         .unwrap_or(8192usize);
+    // init() may create threads, such as the worker thread of non-blocking log writer.
+    postmaster::block_signals().unwrap();
     let gucstate = kuiba::init(bufflog_line_max, datadir).unwrap();
+    // init() has changed the working directory to datadir.
+    let _pidfile = postmaster::lock_datadir(Path::new(".")).unwrap();
     let rt = new_runtime(&gucstate).unwrap();
     rt.block_on(do_main(gucstate));
     return;
@@ -0,0 +1,414 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The row codecs of COPY, compatible with the csv and binary formats of PostgreSQL.
+// They work on fields, the conversion between fields and datums is done by the
+// input/output functions of the column types.
+//
+// The data arrives in CopyData messages whose boundaries are unrelated to rows,
+// so the decoders return Ok(None) if the buffer does not contain a complete row yet,
+// the caller should append more data and try again.
+use crate::{kbbail, kbensure};
+use std::convert::TryInto;
+
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    pub quote: u8,
+    pub escape: u8,
+    pub null: String,
+    pub header: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: b',',
+            quote: b'"',
+            escape: b'"',
+            null: String::new(),
+            header: false,
+        }
+    }
+}
+
+impl CsvOptions {
+    pub fn check(&self) -> anyhow::Result<()> {
+        // The codecs treat them as single chars, a byte >= 0x80 is not a whole UTF-8 char.
+        kbensure!(
+            self.delimiter.is_ascii(),
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "COPY delimiter must be a single one-byte character"
+        );
+        kbensure!(
+            self.quote.is_ascii(),
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "COPY quote must be a single one-byte character"
+        );
+        kbensure!(
+            self.escape.is_ascii(),
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "COPY escape must be a single one-byte character"
+        );
+        kbensure!(
+            self.delimiter != self.quote,
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "COPY delimiter and quote must be different"
+        );
+        kbensure!(
+            self.delimiter != b'\r' && self.delimiter != b'\n',
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "COPY delimiter cannot be newline or carriage return"
+        );
+        kbensure!(
+            !self.null.as_bytes().contains(&self.delimiter),
+            ERRCODE_FEATURE_NOT_SUPPORTED,
+            "COPY delimiter must not appear in the NULL specification"
+        );
+        return Ok(());
+    }
+
+    fn need_quote(&self, field: &str) -> bool {
+        field == self.null
+            || field.bytes().any(|c| {
+                c == self.delimiter
+                    || c == self.quote
+                    || c == self.escape
+                    || c == b'\r'
+                    || c == b'\n'
+            })
+    }
+}
+
+// Write the column names as the first line if HEADER is specified.
+pub fn csv_encode_header(opts: &CsvOptions, names: &[&str], out: &mut String) {
+    if opts.header {
+        let row: Vec<_> = names.iter().map(|&v| Some(v)).collect();
+        csv_encode_row(opts, &row, out);
+    }
+    return;
+}
+
+pub fn csv_encode_row(opts: &CsvOptions, row: &[Option<&str>], out: &mut String) {
+    for (idx, field) in row.iter().enumerate() {
+        if idx > 0 {
+            out.push(opts.delimiter as char);
+        }
+        let field = match field {
+            None => {
+                out.push_str(&opts.null);
+                continue;
+            }
+            Some(v) => v,
+        };
+        if !opts.need_quote(field) {
+            out.push_str(field);
+            continue;
+        }
+        out.push(opts.quote as char);
+        for c in field.chars() {
+            if c == opts.quote as char || c == opts.escape as char {
+                out.push(opts.escape as char);
+            }
+            out.push(c);
+        }
+        out.push(opts.quote as char);
+    }
+    out.push('\n');
+    return;
+}
+
+pub type CsvRow = Vec<Option<String>>;
+
+// Decode one row from buf, return the row and the number of bytes consumed.
+// eof means no more data will be appended, so a last line without newline is a complete row.
+pub fn csv_decode_row(
+    opts: &CsvOptions,
+    buf: &[u8],
+    eof: bool,
+) -> anyhow::Result<Option<(CsvRow, usize)>> {
+    let mut row = Vec::new();
+    let mut field = Vec::new();
+    let mut quoted = false; // whether the current field contains a quoted part.
+    let mut in_quote = false;
+    let mut idx = 0;
+    let end_field = |field: &mut Vec<u8>, quoted: bool, row: &mut CsvRow| -> anyhow::Result<()> {
+        let val = match String::from_utf8(std::mem::take(field)) {
+            Ok(v) => v,
+            Err(_) => kbbail!(
+                ERRCODE_CHARACTER_NOT_IN_REPERTOIRE,
+                "invalid byte sequence for encoding UTF8"
+            ),
+        };
+        row.push(if !quoted && val == opts.null {
+            None
+        } else {
+            Some(val)
+        });
+        return Ok(());
+    };
+    while idx < buf.len() {
+        let c = buf[idx];
+        idx += 1;
+        if in_quote {
+            if c == opts.escape
+                && idx < buf.len()
+                && (buf[idx] == opts.quote || buf[idx] == opts.escape)
+            {
+                field.push(buf[idx]);
+                idx += 1;
+            } else if c == opts.escape && idx >= buf.len() && !eof {
+                // Can not tell whether it is an escape or a literal yet.
+                return Ok(None);
+            } else if c == opts.quote {
+                in_quote = false;
+            } else {
+                field.push(c);
+            }
+        } else if c == opts.quote {
+            in_quote = true;
+            quoted = true;
+        } else if c == opts.delimiter {
+            end_field(&mut field, quoted, &mut row)?;
+            quoted = false;
+        } else if c == b'\n' || c == b'\r' {
+            if c == b'\r' && idx < buf.len() && buf[idx] == b'\n' {
+                idx += 1;
+            } else if c == b'\r' && idx >= buf.len() && !eof {
+                return Ok(None);
+            }
+            end_field(&mut field, quoted, &mut row)?;
+            return Ok(Some((row, idx)));
+        } else {
+            field.push(c);
+        }
+    }
+    if !eof {
+        return Ok(None);
+    }
+    kbensure!(
+        !in_quote,
+        ERRCODE_BAD_COPY_FILE_FORMAT,
+        "unterminated CSV quoted field"
+    );
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    end_field(&mut field, quoted, &mut row)?;
+    return Ok(Some((row, idx)));
+}
+
+// Same as PostgreSQL, the header line is ignored if HEADER is specified.
+// Return the number of bytes consumed, which is 0 if there is no header.
+pub fn csv_skip_header(opts: &CsvOptions, buf: &[u8], eof: bool) -> anyhow::Result<Option<usize>> {
+    if !opts.header {
+        return Ok(Some(0));
+    }
+    return Ok(csv_decode_row(opts, buf, eof)?.map(|(_, len)| len));
+}
+
+const BINARY_SIGNATURE: &[u8; 11] = b"PGCOPY\n\xff\r\n\0";
+const BINARY_HEADER_LEN: usize = 11 + 4 + 4;
+
+pub fn binary_encode_header(out: &mut Vec<u8>) {
+    out.extend_from_slice(BINARY_SIGNATURE);
+    out.extend_from_slice(&0i32.to_be_bytes()); // flags
+    out.extend_from_slice(&0i32.to_be_bytes()); // header extension length
+}
+
+pub fn binary_encode_row(row: &[Option<&[u8]>], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(row.len() as i16).to_be_bytes());
+    for field in row {
+        match field {
+            None => out.extend_from_slice(&(-1i32).to_be_bytes()),
+            Some(v) => {
+                out.extend_from_slice(&(v.len() as i32).to_be_bytes());
+                out.extend_from_slice(v);
+            }
+        }
+    }
+    return;
+}
+
+pub fn binary_encode_trailer(out: &mut Vec<u8>) {
+    out.extend_from_slice(&(-1i16).to_be_bytes());
+}
+
+// Return the number of bytes consumed.
+pub fn binary_decode_header(buf: &[u8]) -> anyhow::Result<Option<usize>> {
+    if buf.len() < BINARY_HEADER_LEN {
+        return Ok(None);
+    }
+    kbensure!(
+        &buf[..11] == BINARY_SIGNATURE,
+        ERRCODE_BAD_COPY_FILE_FORMAT,
+        "COPY file signature not recognized"
+    );
+    let flags = i32::from_be_bytes(buf[11..15].try_into().unwrap());
+    kbensure!(
+        flags & 0xffff0000u32 as i32 == 0,
+        ERRCODE_BAD_COPY_FILE_FORMAT,
+        "unrecognized critical flags in COPY file header. flags={}",
+        flags
+    );
+    let extlen = i32::from_be_bytes(buf[15..19].try_into().unwrap());
+    kbensure!(
+        extlen >= 0,
+        ERRCODE_BAD_COPY_FILE_FORMAT,
+        "invalid COPY file header. extlen={}",
+        extlen
+    );
+    let len = BINARY_HEADER_LEN + extlen as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    return Ok(Some(len));
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BinaryRow {
+    Row(Vec<Option<Vec<u8>>>),
+    Trailer,
+}
+
+pub fn binary_decode_row(buf: &[u8]) -> anyhow::Result<Option<(BinaryRow, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let nfields = i16::from_be_bytes(buf[..2].try_into().unwrap());
+    if nfields == -1 {
+        return Ok(Some((BinaryRow::Trailer, 2)));
+    }
+    kbensure!(
+        nfields >= 0,
+        ERRCODE_BAD_COPY_FILE_FORMAT,
+        "invalid field count. nfields={}",
+        nfields
+    );
+    let mut off = 2;
+    let mut row = Vec::with_capacity(nfields as usize);
+    for _ in 0..nfields {
+        if buf.len() < off + 4 {
+            return Ok(None);
+        }
+        let len = i32::from_be_bytes(buf[off..off + 4].try_into().unwrap());
+        off += 4;
+        if len == -1 {
+            row.push(None);
+            continue;
+        }
+        kbensure!(
+            len >= 0,
+            ERRCODE_BAD_COPY_FILE_FORMAT,
+            "invalid field size. len={}",
+            len
+        );
+        let len = len as usize;
+        if buf.len() < off + len {
+            return Ok(None);
+        }
+        row.push(Some(buf[off..off + len].to_vec()));
+        off += len;
+    }
+    return Ok(Some((BinaryRow::Row(row), off)));
+}
+
+#[cfg(test)]
+mod copy_test {
+    use super::{
+        binary_decode_header, binary_decode_row, binary_encode_header, binary_encode_row,
+        binary_encode_trailer, csv_decode_row, csv_encode_header, csv_encode_row, csv_skip_header,
+        BinaryRow, CsvOptions,
+    };
+    use crate::protocol::ERRCODE_FEATURE_NOT_SUPPORTED;
+    use crate::utils::err::errcode;
+
+    #[test]
+    fn f() {
+        let opts = CsvOptions::default();
+        assert!(opts.check().is_ok());
+        for opts in &[
+            CsvOptions {
+                delimiter: 0xe4,
+                ..CsvOptions::default()
+            },
+            CsvOptions {
+                quote: 0x80,
+                ..CsvOptions::default()
+            },
+            CsvOptions {
+                escape: 0xff,
+                ..CsvOptions::default()
+            },
+        ] {
+            let err = opts.check().unwrap_err();
+            assert_eq!(errcode(&err), ERRCODE_FEATURE_NOT_SUPPORTED);
+        }
+        let mut out = String::new();
+        csv_encode_row(
+            &opts,
+            &[Some("a,b"), None, Some(""), Some("x\"y\nz")],
+            &mut out,
+        );
+        assert_eq!(out, "\"a,b\",,\"\",\"x\"\"y\nz\"\n");
+        let data = out.as_bytes();
+        for cut in 0..data.len() {
+            assert!(csv_decode_row(&opts, &data[..cut], false)
+                .unwrap()
+                .is_none());
+        }
+        let (row, len) = csv_decode_row(&opts, data, false).unwrap().unwrap();
+        assert_eq!(len, data.len());
+        assert_eq!(
+            row,
+            vec![
+                Some("a,b".to_string()),
+                None,
+                Some("".to_string()),
+                Some("x\"y\nz".to_string())
+            ]
+        );
+        let (row, _) = csv_decode_row(&opts, b"1,2", true).unwrap().unwrap();
+        assert_eq!(row, vec![Some("1".to_string()), Some("2".to_string())]);
+        assert!(csv_decode_row(&opts, b"\"1,2", true).is_err());
+
+        let mut out = String::new();
+        csv_encode_header(&opts, &["a", "b"], &mut out);
+        assert!(out.is_empty());
+        assert_eq!(csv_skip_header(&opts, b"a,b\n", false).unwrap(), Some(0));
+        let opts = CsvOptions {
+            header: true,
+            ..CsvOptions::default()
+        };
+        csv_encode_header(&opts, &["a", "b,c"], &mut out);
+        csv_encode_row(&opts, &[Some("1"), Some("2")], &mut out);
+        assert_eq!(out, "a,\"b,c\"\n1,2\n");
+        assert!(csv_skip_header(&opts, b"a,b", false).unwrap().is_none());
+        let off = csv_skip_header(&opts, out.as_bytes(), false)
+            .unwrap()
+            .unwrap();
+        let (row, _) = csv_decode_row(&opts, &out.as_bytes()[off..], false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(row, vec![Some("1".to_string()), Some("2".to_string())]);
+
+        let mut out = Vec::new();
+        binary_encode_header(&mut out);
+        binary_encode_row(&[Some(b"hello"), None], &mut out);
+        binary_encode_trailer(&mut out);
+        let off = binary_decode_header(&out).unwrap().unwrap();
+        assert!(binary_decode_row(&out[off..off + 8]).unwrap().is_none());
+        let (row, len) = binary_decode_row(&out[off..]).unwrap().unwrap();
+        assert_eq!(row, BinaryRow::Row(vec![Some(b"hello".to_vec()), None]));
+        let (row, _) = binary_decode_row(&out[off + len..]).unwrap().unwrap();
+        assert_eq!(row, BinaryRow::Trailer);
+    }
+}
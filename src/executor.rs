@@ -0,0 +1,204 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The vectorized executor. Operators are pulled in the Volcano style, but every
+// next() returns a batch of at most BATCH_SIZE rows stored column by column,
+// and expressions are evaluated by kernels over the whole column.
+use crate::kbensure;
+use std::collections::VecDeque;
+use vector::{BinOp, Column};
+
+pub mod vector;
+
+pub const BATCH_SIZE: usize = 2048;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub cols: Vec<Column>,
+    pub len: usize,
+}
+
+impl Batch {
+    pub fn new(cols: Vec<Column>) -> anyhow::Result<Batch> {
+        let len = cols.first().map_or(0, |v| v.len());
+        kbensure!(
+            cols.iter().all(|v| v.len() == len),
+            ERRCODE_INTERNAL_ERROR,
+            "column length mismatch in batch"
+        );
+        kbensure!(
+            len <= BATCH_SIZE,
+            ERRCODE_INTERNAL_ERROR,
+            "batch too large. len={}",
+            len
+        );
+        return Ok(Batch { cols, len });
+    }
+
+    fn take(&self, sel: &[u32]) -> Batch {
+        Batch {
+            cols: self.cols.iter().map(|v| v.take(sel)).collect(),
+            len: sel.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Column(usize),
+    // A single-row column, broadcasted to the length of the batch.
+    Const(Column),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn eval(&self, batch: &Batch) -> anyhow::Result<Column> {
+        match self {
+            Expr::Column(idx) => {
+                kbensure!(
+                    *idx < batch.cols.len(),
+                    ERRCODE_INTERNAL_ERROR,
+                    "column index out of range. idx={} ncols={}",
+                    idx,
+                    batch.cols.len()
+                );
+                Ok(batch.cols[*idx].clone())
+            }
+            Expr::Const(v) => v.broadcast(batch.len),
+            Expr::Binary(op, l, r) => vector::binary(*op, &l.eval(batch)?, &r.eval(batch)?),
+        }
+    }
+}
+
+pub trait Operator {
+    // Return None if there is no more rows. A returned batch is never empty.
+    fn next(&mut self) -> anyhow::Result<Option<Batch>>;
+}
+
+// Return the batches given at construction, used by VALUES and tests.
+pub struct Values {
+    batches: VecDeque<Batch>,
+}
+
+impl Values {
+    pub fn new(batches: Vec<Batch>) -> Values {
+        Values {
+            batches: batches.into_iter().filter(|v| v.len > 0).collect(),
+        }
+    }
+}
+
+impl Operator for Values {
+    fn next(&mut self) -> anyhow::Result<Option<Batch>> {
+        Ok(self.batches.pop_front())
+    }
+}
+
+pub struct Filter {
+    input: Box<dyn Operator>,
+    pred: Expr,
+}
+
+impl Filter {
+    pub fn new(input: Box<dyn Operator>, pred: Expr) -> Filter {
+        Filter { input, pred }
+    }
+}
+
+impl Operator for Filter {
+    fn next(&mut self) -> anyhow::Result<Option<Batch>> {
+        while let Some(batch) = self.input.next()? {
+            let sel = vector::selection(&self.pred.eval(&batch)?)?;
+            if sel.is_empty() {
+                continue;
+            }
+            if sel.len() == batch.len {
+                return Ok(Some(batch));
+            }
+            return Ok(Some(batch.take(&sel)));
+        }
+        return Ok(None);
+    }
+}
+
+pub struct Project {
+    input: Box<dyn Operator>,
+    exprs: Vec<Expr>,
+}
+
+impl Project {
+    pub fn new(input: Box<dyn Operator>, exprs: Vec<Expr>) -> Project {
+        Project { input, exprs }
+    }
+}
+
+impl Operator for Project {
+    fn next(&mut self) -> anyhow::Result<Option<Batch>> {
+        let batch = match self.input.next()? {
+            None => return Ok(None),
+            Some(v) => v,
+        };
+        let cols = self
+            .exprs
+            .iter()
+            .map(|v| v.eval(&batch))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        return Ok(Some(Batch {
+            cols,
+            len: batch.len,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod executor_test {
+    use super::vector::{BinOp, Bitmap, Column, Vector};
+    use super::{Batch, Expr, Filter, Operator, Project, Values};
+
+    #[test]
+    fn f() {
+        let mut nulls = Bitmap::new(4);
+        nulls.set(3, true);
+        let b1 = Batch::new(vec![
+            Column::with_nulls(Vector::Int8(vec![1, 2, 3, 4]), nulls),
+            Column::new(Vector::Varchar(
+                ["a", "b", "c", "d"].iter().map(|v| v.to_string()).collect(),
+            )),
+        ])
+        .unwrap();
+        let b2 = Batch::new(vec![
+            Column::new(Vector::Int8(vec![0])),
+            Column::new(Vector::Varchar(vec!["e".to_string()])),
+        ])
+        .unwrap();
+        let values = Values::new(vec![b1, b2]);
+        let pred = Expr::Binary(
+            BinOp::Gt,
+            Box::new(Expr::Column(0)),
+            Box::new(Expr::Const(Column::new(Vector::Int8(vec![1])))),
+        );
+        let filter = Filter::new(Box::new(values), pred);
+        let times10 = Expr::Binary(
+            BinOp::Mul,
+            Box::new(Expr::Column(0)),
+            Box::new(Expr::Const(Column::new(Vector::Int8(vec![10])))),
+        );
+        let mut project = Project::new(Box::new(filter), vec![Expr::Column(1), times10]);
+        let batch = project.next().unwrap().unwrap();
+        assert_eq!(batch.len, 2);
+        assert_eq!(
+            batch.cols[0].vals,
+            Vector::Varchar(vec!["b".to_string(), "c".to_string()])
+        );
+        assert_eq!(batch.cols[1].vals, Vector::Int8(vec![20, 30]));
+        assert!(project.next().unwrap().is_none());
+    }
+}
@@ -0,0 +1,466 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Typed column vectors and the kernels working on them.
+use crate::{kbanyhow, kbbail, kbensure};
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Bitmap {
+    pub fn new(len: usize) -> Bitmap {
+        Bitmap {
+            words: vec![0; (len + 63) / 64],
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, idx: usize) -> bool {
+        debug_assert!(idx < self.len);
+        (self.words[idx / 64] >> (idx % 64)) & 1 == 1
+    }
+
+    pub fn set(&mut self, idx: usize, val: bool) {
+        debug_assert!(idx < self.len);
+        if val {
+            self.words[idx / 64] |= 1 << (idx % 64);
+        } else {
+            self.words[idx / 64] &= !(1 << (idx % 64));
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|v| v.count_ones() as usize).sum()
+    }
+
+    fn or(&self, other: &Bitmap) -> Bitmap {
+        debug_assert_eq!(self.len, other.len);
+        Bitmap {
+            words: self
+                .words
+                .iter()
+                .zip(&other.words)
+                .map(|(l, r)| l | r)
+                .collect(),
+            len: self.len,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Vector {
+    Bool(Vec<bool>),
+    Int4(Vec<i32>),
+    Int8(Vec<i64>),
+    Float8(Vec<f64>),
+    Varchar(Vec<String>),
+}
+
+macro_rules! vector_dispatch {
+    ($vec: expr, $v: ident => $body: expr) => {
+        match $vec {
+            Vector::Bool($v) => Vector::Bool($body),
+            Vector::Int4($v) => Vector::Int4($body),
+            Vector::Int8($v) => Vector::Int8($body),
+            Vector::Float8($v) => Vector::Float8($body),
+            Vector::Varchar($v) => Vector::Varchar($body),
+        }
+    };
+}
+
+impl Vector {
+    pub fn len(&self) -> usize {
+        match self {
+            Vector::Bool(v) => v.len(),
+            Vector::Int4(v) => v.len(),
+            Vector::Int8(v) => v.len(),
+            Vector::Float8(v) => v.len(),
+            Vector::Varchar(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Vector::Bool(_) => "bool",
+            Vector::Int4(_) => "int4",
+            Vector::Int8(_) => "int8",
+            Vector::Float8(_) => "float8",
+            Vector::Varchar(_) => "varchar",
+        }
+    }
+}
+
+// A bit set in nulls means the value is NULL, and the value in vals is undefined.
+// nulls is None if there is no NULL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub vals: Vector,
+    pub nulls: Option<Bitmap>,
+}
+
+impl Column {
+    pub fn new(vals: Vector) -> Column {
+        Column { vals, nulls: None }
+    }
+
+    pub fn with_nulls(vals: Vector, nulls: Bitmap) -> Column {
+        debug_assert_eq!(vals.len(), nulls.len());
+        Column {
+            vals,
+            nulls: Some(nulls),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.vals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vals.is_empty()
+    }
+
+    pub fn is_null(&self, idx: usize) -> bool {
+        self.nulls.as_ref().map_or(false, |v| v.get(idx))
+    }
+
+    // Repeat the only value len times, used to evaluate constants.
+    pub fn broadcast(&self, len: usize) -> anyhow::Result<Column> {
+        kbensure!(
+            self.len() == 1,
+            ERRCODE_INTERNAL_ERROR,
+            "broadcast a column which is not single-row. len={}",
+            self.len()
+        );
+        let vals = vector_dispatch!(&self.vals, v => {
+            std::iter::repeat(&v[0]).take(len).cloned().collect()
+        });
+        let nulls = if self.is_null(0) {
+            let mut nulls = Bitmap::new(len);
+            (0..len).for_each(|i| nulls.set(i, true));
+            Some(nulls)
+        } else {
+            None
+        };
+        return Ok(Column { vals, nulls });
+    }
+
+    // Gather the rows in sel.
+    pub fn take(&self, sel: &[u32]) -> Column {
+        let vals = vector_dispatch!(&self.vals, v => {
+            sel.iter().map(|&i| &v[i as usize]).cloned().collect()
+        });
+        let nulls = self.nulls.as_ref().map(|nulls| {
+            let mut newnulls = Bitmap::new(sel.len());
+            for (idx, &i) in sel.iter().enumerate() {
+                newnulls.set(idx, nulls.get(i as usize));
+            }
+            newnulls
+        });
+        Column { vals, nulls }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+fn merge_nulls(l: &Option<Bitmap>, r: &Option<Bitmap>) -> Option<Bitmap> {
+    match (l, r) {
+        (None, None) => None,
+        (Some(v), None) | (None, Some(v)) => Some(v.clone()),
+        (Some(l), Some(r)) => Some(l.or(r)),
+    }
+}
+
+fn is_null(nulls: &Option<Bitmap>, idx: usize) -> bool {
+    nulls.as_ref().map_or(false, |v| v.get(idx))
+}
+
+// The function is not called for NULL rows, so that the undefined values
+// in them do not cause spurious errors.
+fn map2<T: Copy, R: Default>(
+    l: &[T],
+    r: &[T],
+    nulls: &Option<Bitmap>,
+    f: impl Fn(T, T) -> anyhow::Result<R>,
+) -> anyhow::Result<Vec<R>> {
+    let mut out = Vec::with_capacity(l.len());
+    for (idx, (&lv, &rv)) in l.iter().zip(r).enumerate() {
+        if is_null(nulls, idx) {
+            out.push(R::default());
+        } else {
+            out.push(f(lv, rv)?);
+        }
+    }
+    return Ok(out);
+}
+
+fn out_of_range(typ: &str) -> anyhow::Error {
+    kbanyhow!(ERRCODE_NUMERIC_VALUE_OUT_OF_RANGE, "{} out of range", typ)
+}
+
+fn div_by_zero() -> anyhow::Error {
+    kbanyhow!(ERRCODE_DIVISION_BY_ZERO, "division by zero")
+}
+
+macro_rules! int_arith {
+    ($op: expr, $l: expr, $r: expr, $nulls: expr, $typ: literal) => {
+        match $op {
+            BinOp::Add => map2($l, $r, $nulls, |l, r| {
+                l.checked_add(r).ok_or_else(|| out_of_range($typ))
+            }),
+            BinOp::Sub => map2($l, $r, $nulls, |l, r| {
+                l.checked_sub(r).ok_or_else(|| out_of_range($typ))
+            }),
+            BinOp::Mul => map2($l, $r, $nulls, |l, r| {
+                l.checked_mul(r).ok_or_else(|| out_of_range($typ))
+            }),
+            _ => map2($l, $r, $nulls, |l, r| {
+                if r == 0 {
+                    return Err(div_by_zero());
+                }
+                l.checked_div(r).ok_or_else(|| out_of_range($typ))
+            }),
+        }
+    };
+}
+
+fn float_arith(
+    op: BinOp,
+    l: &[f64],
+    r: &[f64],
+    nulls: &Option<Bitmap>,
+) -> anyhow::Result<Vec<f64>> {
+    map2(l, r, nulls, |l, r| {
+        let v = match op {
+            BinOp::Add => l + r,
+            BinOp::Sub => l - r,
+            BinOp::Mul => l * r,
+            _ => {
+                if r == 0.0 {
+                    return Err(div_by_zero());
+                }
+                l / r
+            }
+        };
+        // Same as float8pl() etc. of PostgreSQL.
+        if v.is_infinite() && !l.is_infinite() && !r.is_infinite() {
+            return Err(out_of_range("float8"));
+        }
+        return Ok(v);
+    })
+}
+
+// Same as float8_cmp_internal() of PostgreSQL, NaN is equal to NaN and greater than
+// any non-NaN value, so that the float8 values are totally ordered.
+fn float8_cmp(l: &f64, r: &f64) -> Ordering {
+    match (l.is_nan(), r.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => l.partial_cmp(r).unwrap(),
+    }
+}
+
+fn compare<T, F: Fn(&T, &T) -> Ordering>(op: BinOp, l: &[T], r: &[T], cmp: F) -> Vec<bool> {
+    l.iter()
+        .zip(r)
+        .map(|(l, r)| {
+            let ord = cmp(l, r);
+            match op {
+                BinOp::Eq => ord == Ordering::Equal,
+                BinOp::Ne => ord != Ordering::Equal,
+                BinOp::Lt => ord == Ordering::Less,
+                BinOp::Le => ord != Ordering::Greater,
+                BinOp::Gt => ord == Ordering::Greater,
+                _ => ord != Ordering::Less,
+            }
+        })
+        .collect()
+}
+
+// AND/OR use the three-valued logic, `false AND NULL` is false and `true OR NULL` is true.
+fn logic(op: BinOp, l: &Column, r: &Column, lv: &[bool], rv: &[bool]) -> Column {
+    let mut vals = Vec::with_capacity(lv.len());
+    let mut nulls = Bitmap::new(lv.len());
+    for (idx, (&lb, &rb)) in lv.iter().zip(rv).enumerate() {
+        let lval = if l.is_null(idx) { None } else { Some(lb) };
+        let rval = if r.is_null(idx) { None } else { Some(rb) };
+        let res = match (op, lval, rval) {
+            (BinOp::And, Some(false), _) | (BinOp::And, _, Some(false)) => Some(false),
+            (BinOp::And, Some(true), Some(true)) => Some(true),
+            (BinOp::Or, Some(true), _) | (BinOp::Or, _, Some(true)) => Some(true),
+            (BinOp::Or, Some(false), Some(false)) => Some(false),
+            _ => None,
+        };
+        vals.push(res.unwrap_or(false));
+        nulls.set(idx, res.is_none());
+    }
+    if nulls.count_ones() == 0 {
+        Column::new(Vector::Bool(vals))
+    } else {
+        Column::with_nulls(Vector::Bool(vals), nulls)
+    }
+}
+
+// The operands must have the same type, the planner should have inserted the casts.
+pub fn binary(op: BinOp, l: &Column, r: &Column) -> anyhow::Result<Column> {
+    kbensure!(
+        l.len() == r.len(),
+        ERRCODE_INTERNAL_ERROR,
+        "operand length mismatch. left={} right={}",
+        l.len(),
+        r.len()
+    );
+    let nulls = merge_nulls(&l.nulls, &r.nulls);
+    let vals = match (op, &l.vals, &r.vals) {
+        (BinOp::And, Vector::Bool(lv), Vector::Bool(rv))
+        | (BinOp::Or, Vector::Bool(lv), Vector::Bool(rv)) => {
+            return Ok(logic(op, l, r, lv, rv));
+        }
+        (BinOp::Add, ..) | (BinOp::Sub, ..) | (BinOp::Mul, ..) | (BinOp::Div, ..) => {
+            match (&l.vals, &r.vals) {
+                (Vector::Int4(lv), Vector::Int4(rv)) => {
+                    Vector::Int4(int_arith!(op, lv, rv, &nulls, "integer")?)
+                }
+                (Vector::Int8(lv), Vector::Int8(rv)) => {
+                    Vector::Int8(int_arith!(op, lv, rv, &nulls, "bigint")?)
+                }
+                (Vector::Float8(lv), Vector::Float8(rv)) => {
+                    Vector::Float8(float_arith(op, lv, rv, &nulls)?)
+                }
+                _ => kbbail!(
+                    ERRCODE_DATATYPE_MISMATCH,
+                    "operator does not exist. op={:?} left={} right={}",
+                    op,
+                    l.vals.type_name(),
+                    r.vals.type_name()
+                ),
+            }
+        }
+        (BinOp::And, ..) | (BinOp::Or, ..) => kbbail!(
+            ERRCODE_DATATYPE_MISMATCH,
+            "argument of {:?} must be type boolean. left={} right={}",
+            op,
+            l.vals.type_name(),
+            r.vals.type_name()
+        ),
+        (_, Vector::Bool(lv), Vector::Bool(rv)) => Vector::Bool(compare(op, lv, rv, Ord::cmp)),
+        (_, Vector::Int4(lv), Vector::Int4(rv)) => Vector::Bool(compare(op, lv, rv, Ord::cmp)),
+        (_, Vector::Int8(lv), Vector::Int8(rv)) => Vector::Bool(compare(op, lv, rv, Ord::cmp)),
+        (_, Vector::Float8(lv), Vector::Float8(rv)) => {
+            Vector::Bool(compare(op, lv, rv, float8_cmp))
+        }
+        (_, Vector::Varchar(lv), Vector::Varchar(rv)) => {
+            Vector::Bool(compare(op, lv, rv, Ord::cmp))
+        }
+        _ => kbbail!(
+            ERRCODE_DATATYPE_MISMATCH,
+            "operator does not exist. op={:?} left={} right={}",
+            op,
+            l.vals.type_name(),
+            r.vals.type_name()
+        ),
+    };
+    return Ok(Column { vals, nulls });
+}
+
+// Return the rows whose predicate is true, NULL is treated as false.
+pub fn selection(pred: &Column) -> anyhow::Result<Vec<u32>> {
+    let vals = match &pred.vals {
+        Vector::Bool(v) => v,
+        v => kbbail!(
+            ERRCODE_DATATYPE_MISMATCH,
+            "argument of WHERE must be type boolean. type={}",
+            v.type_name()
+        ),
+    };
+    let sel = (0..vals.len())
+        .filter(|&idx| vals[idx] && !pred.is_null(idx))
+        .map(|idx| idx as u32)
+        .collect();
+    return Ok(sel);
+}
+
+#[cfg(test)]
+mod vector_test {
+    use super::{binary, selection, BinOp, Bitmap, Column, Vector};
+    use crate::protocol::{
+        ERRCODE_DIVISION_BY_ZERO, ERRCODE_INTERNAL_ERROR, ERRCODE_NUMERIC_VALUE_OUT_OF_RANGE,
+    };
+    use crate::utils::err::errcode;
+
+    #[test]
+    fn f() {
+        let mut nulls = Bitmap::new(3);
+        nulls.set(1, true);
+        let l = Column::with_nulls(Vector::Int4(vec![1, 0, i32::MAX]), nulls);
+        let r = Column::new(Vector::Int4(vec![2, 0, 0]));
+        let err = binary(BinOp::Add, &l, &Column::new(Vector::Int4(vec![1, 1, 1]))).unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_NUMERIC_VALUE_OUT_OF_RANGE);
+        let err = binary(BinOp::Div, &l, &r).unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_DIVISION_BY_ZERO);
+        let res = binary(BinOp::Mul, &l, &Column::new(Vector::Int4(vec![3, 3, 0]))).unwrap();
+        assert_eq!(res.vals, Vector::Int4(vec![3, 0, 0]));
+        assert!(res.is_null(1));
+
+        let pred = binary(BinOp::Lt, &l, &r).unwrap();
+        assert_eq!(selection(&pred).unwrap(), vec![0]);
+        let f = Column::new(Vector::Bool(vec![false, false, false]));
+        let and = binary(BinOp::And, &pred, &f).unwrap();
+        assert_eq!(and.nulls, None);
+        let or = binary(BinOp::Or, &pred, &f).unwrap();
+        assert!(or.is_null(1));
+        assert_eq!(selection(&or).unwrap(), vec![0]);
+
+        let c = Column::new(Vector::Int4(vec![7])).broadcast(3).unwrap();
+        assert_eq!(c.vals, Vector::Int4(vec![7, 7, 7]));
+        let err = Column::new(Vector::Int4(vec![])).broadcast(3).unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_INTERNAL_ERROR);
+
+        let l = Column::new(Vector::Float8(vec![f64::NAN, f64::NAN, 1.0]));
+        let r = Column::new(Vector::Float8(vec![f64::NAN, f64::INFINITY, f64::NAN]));
+        let eq = binary(BinOp::Eq, &l, &r).unwrap();
+        assert_eq!(eq.vals, Vector::Bool(vec![true, false, false]));
+        let gt = binary(BinOp::Gt, &l, &r).unwrap();
+        assert_eq!(gt.vals, Vector::Bool(vec![false, true, false]));
+        let le = binary(BinOp::Le, &l, &r).unwrap();
+        assert_eq!(le.vals, Vector::Bool(vec![true, false, true]));
+    }
+}
@@ -34,9 +34,20 @@ enum Context {
 // bit values in "flags" of a GUC variable
 const REPORT: u32 = 0x0010;
 
+// The indexes of the GUCs whose values come from the SET command, they are kept
+// when the session applies the reloaded config file.
+#[derive(Clone, Default)]
+pub struct SetGucs {
+    bool_idx: Vec<usize>,
+    int_idx: Vec<usize>,
+    real_idx: Vec<usize>,
+    str_idx: Vec<usize>,
+}
+
 #[derive(Clone)]
 pub struct GucState {
     pub vals: GucVals,
+    setgucs: SetGucs,
     // other state derived from guc should be placed here.
     pub base_search_path_valid: bool,
 }
@@ -45,6 +56,7 @@ impl Default for GucState {
     fn default() -> Self {
         GucState {
             vals: GucVals::default(),
+            setgucs: SetGucs::default(),
             base_search_path_valid: false,
         }
     }
@@ -79,10 +91,11 @@ enum GucMeta {
     S(&'static Str),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Source {
-    FILE, // kuiba.conf
-    SET,  // SET command
+    FILE,   // kuiba.conf
+    SET,    // SET command
+    RELOAD, // kuiba.conf reloaded on SIGHUP
 }
 
 pub fn get_gucidx(name: &str) -> Option<GucIdx> {
@@ -144,6 +157,7 @@ fn preassign(gucgen: &Generic, gucsrc: Source) -> bool {
     let ret = match gucsrc {
         Source::FILE => gucgen.context != Context::Internal,
         Source::SET => gucgen.context >= Context::SuSet,
+        Source::RELOAD => gucgen.context >= Context::SigHup,
     };
     if !ret {
         warn!(
@@ -155,7 +169,7 @@ fn preassign(gucgen: &Generic, gucsrc: Source) -> bool {
 }
 
 macro_rules! def_apply_fn {
-    ($fnname: ident, $valty: ident, $valarr: ident, $metaarr: ident) => {
+    ($fnname: ident, $valty: ident, $valarr: ident, $metaarr: ident, $setarr: ident) => {
         fn $fnname(idx: usize, mut val: $valty, gucstate: &mut GucState, gucsrc: Source) {
             let meta = &$metaarr[idx];
             if preassign(&meta.gen, gucsrc)
@@ -163,15 +177,21 @@ macro_rules! def_apply_fn {
             {
                 let gucvalptr = &mut gucstate.vals.$valarr[idx];
                 *gucvalptr = val;
+                let setarr = &mut gucstate.setgucs.$setarr;
+                if let Source::SET = gucsrc {
+                    if !setarr.contains(&idx) {
+                        setarr.push(idx);
+                    }
+                }
             }
         }
     };
 }
 
-def_apply_fn!(apply_int_guc, i32, int_vals, INT_GUCS);
-def_apply_fn!(apply_bool_guc, bool, bool_vals, BOOL_GUCS);
-def_apply_fn!(apply_real_guc, f64, real_vals, REAL_GUCS);
-def_apply_fn!(apply_str_guc, String, str_vals, STR_GUCS);
+def_apply_fn!(apply_int_guc, i32, int_vals, INT_GUCS, int_idx);
+def_apply_fn!(apply_bool_guc, bool, bool_vals, BOOL_GUCS, bool_idx);
+def_apply_fn!(apply_real_guc, f64, real_vals, REAL_GUCS, real_idx);
+def_apply_fn!(apply_str_guc, String, str_vals, STR_GUCS, str_idx);
 
 pub fn set_int_guc(idx: gucdef::I, val: i32, gucstate: &mut GucState) {
     apply_int_guc(idx as usize, val, gucstate, Source::SET);
@@ -189,11 +209,11 @@ pub fn set_bool_guc(idx: gucdef::B, val: bool, gucstate: &mut GucState) {
     apply_bool_guc(idx as usize, val, gucstate, Source::SET);
 }
 
-fn load_guc(gucstate: &mut GucState, guckey: &str, gucval: &Yaml) {
+fn load_guc(gucstate: &mut GucState, guckey: &str, gucval: &Yaml, gucsrc: Source) {
     macro_rules! apply_guc {
         ($yamlto: ident, $apply: ident, $idx: expr) => {
             if let Some(val) = common::$yamlto(gucval) {
-                $apply($idx as usize, val, gucstate, Source::FILE);
+                $apply($idx as usize, val, gucstate, gucsrc);
             } else {
                 warn!(
                     "invalid guc val. expected={}, guckey={:?} gucval={:?}",
@@ -213,8 +233,7 @@ fn load_guc(gucstate: &mut GucState, guckey: &str, gucval: &Yaml) {
     }
 }
 
-pub fn load(inputpath: &str) -> anyhow::Result<GucState> {
-    let mut gucstate = GucState::default();
+fn load_file(gucstate: &mut GucState, inputpath: &str, gucsrc: Source) -> anyhow::Result<()> {
     let yamldata = common::load_yaml(inputpath)?;
     if let Some(yamldoc) = yamldata.first() {
         let yamlhash = yamldoc
@@ -223,7 +242,7 @@ pub fn load(inputpath: &str) -> anyhow::Result<GucState> {
         for (gucname, gucval) in yamlhash {
             let guckey = common::yaml_try_tostr(gucname);
             if let Some(guckey) = guckey {
-                load_guc(&mut gucstate, &guckey, gucval);
+                load_guc(gucstate, &guckey, gucval, gucsrc);
             } else {
                 warn!(
                     "Unknown gucname. yaml_try_tostr failed. gucname={:?}",
@@ -232,9 +251,45 @@ pub fn load(inputpath: &str) -> anyhow::Result<GucState> {
             }
         }
     }
+    return Ok(());
+}
+
+pub fn load(inputpath: &str) -> anyhow::Result<GucState> {
+    let mut gucstate = GucState::default();
+    load_file(&mut gucstate, inputpath, Source::FILE)?;
+    return Ok(gucstate);
+}
+
+// Reload the config file, only the GUCs whose context >= SigHup will be changed.
+pub fn reload(gucstate: &GucState, inputpath: &str) -> anyhow::Result<GucState> {
+    let mut gucstate = gucstate.clone();
+    load_file(&mut gucstate, inputpath, Source::RELOAD)?;
     return Ok(gucstate);
 }
 
+// Used by sessions to apply the reloaded config file, the GUCs set by the session
+// keep their values, the others are taken from reloaded.
+pub fn merge_reloaded(sess: &GucState, reloaded: &GucState) -> GucState {
+    let mut gucstate = reloaded.clone();
+    let setgucs = &sess.setgucs;
+    for &idx in &setgucs.bool_idx {
+        gucstate.vals.bool_vals[idx] = sess.vals.bool_vals[idx];
+    }
+    for &idx in &setgucs.int_idx {
+        gucstate.vals.int_vals[idx] = sess.vals.int_vals[idx];
+    }
+    for &idx in &setgucs.real_idx {
+        gucstate.vals.real_vals[idx] = sess.vals.real_vals[idx];
+    }
+    for &idx in &setgucs.str_idx {
+        gucstate.vals.str_vals[idx] = sess.vals.str_vals[idx].clone();
+    }
+    gucstate.setgucs = setgucs.clone();
+    // search_path may be changed by the reload.
+    gucstate.base_search_path_valid = false;
+    return gucstate;
+}
+
 pub fn get_int(gucvals: &GucState, guckey: gucdef::I) -> i32 {
     gucvals.vals.int_vals[guckey as usize]
 }
@@ -267,6 +322,15 @@ fn log_min_messages_preassign(val: &mut String, _gucstate: &mut GucState) -> boo
     true
 }
 
+// work_mem, query_mem_limit and session_mem_limit, there is no -1 for them.
+fn mem_kb_preassign(val: &mut i32, _gucstate: &mut GucState) -> bool {
+    if *val < 0 {
+        warn!("memory size can not be negative. val={}", val);
+        return false;
+    }
+    true
+}
+
 fn search_path_preassign(_val: &mut String, gucstate: &mut GucState) -> bool {
     gucstate.base_search_path_valid = false;
     true
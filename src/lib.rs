@@ -10,7 +10,7 @@
 // limitations under the License.
 use crate::guc::GucState;
 use crate::io::Stream;
-use crate::utils::err::errcode;
+use crate::utils::err::{errcode, errctx};
 use anyhow::Context;
 use kbio::FdGuard;
 use kbio::Uring;
@@ -18,10 +18,11 @@ pub use oids::*;
 #[cfg(debug_assertions)]
 use std::io::Stdout;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering::Relaxed};
 use std::sync::Arc;
-use tokio::io::{AsyncWriteExt, BufStream};
-use tracing::{error, info, trace};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+use tracing::{error, info, trace, warn};
 #[cfg(not(debug_assertions))]
 use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder};
 use tracing_subscriber::filter::EnvFilter;
@@ -30,10 +31,16 @@ use tracing_subscriber::fmt::Formatter;
 use tracing_subscriber::reload::Handle;
 
 mod common;
+pub mod copy;
+pub mod executor;
 pub mod guc;
 mod io;
+pub mod notify;
 mod oids;
+pub mod postmaster;
 mod protocol;
+pub mod smgr;
+pub mod stat;
 mod utils;
 
 fn make_static<T>(v: T) -> &'static T {
@@ -52,6 +59,7 @@ static mut LOG_FILTER_RELOAD_HANDLER: Option<&'static HandleType> = None;
 
 // change the server_version in gucdef.yaml and Cargo.toml TOO!
 pub const KB_VERSTR: &str = "0.0.1";
+pub const KB_CONF: &str = "kuiba.conf";
 
 // called at the entry point of the process.
 fn init_log(#[cfg(not(debug_assertions))] lines_limit: usize) {
@@ -99,8 +107,9 @@ pub fn init(_lines_limit: usize, datadir: &str) -> anyhow::Result<GucState> {
         #[cfg(not(debug_assertions))]
         _lines_limit,
     );
+    postmaster::save_start_dir()?;
     std::env::set_current_dir(datadir)?;
-    let gucstate = guc::load("kuiba.conf")?;
+    let gucstate = guc::load(KB_CONF)?;
     return Ok(gucstate);
 }
 
@@ -159,12 +168,43 @@ impl Urings {
 pub struct GlobalState {
     pub gucstate: Arc<guc::GucState>,
     pub urings: &'static Urings,
+    pub pm: &'static postmaster::Postmaster,
+    pub notify: &'static notify::Hub,
+    pub stat: &'static stat::Stat,
+    pub(crate) tmpdirs: &'static utils::tmpfile::TempDirs,
+    sessid: &'static AtomicU32,
 }
 
 impl GlobalState {
     pub fn new(gucstate: Arc<guc::GucState>) -> anyhow::Result<GlobalState> {
         let urings = make_static(Urings::new(&gucstate)?);
-        return Ok(GlobalState { gucstate, urings });
+        let pm = make_static(postmaster::Postmaster::new(gucstate.clone()));
+        let notify_queue_cap = guc::get_int(&gucstate, guc::MaxNotifyQueueLen) as usize;
+        let notify = make_static(notify::Hub::new(notify_queue_cap));
+        let stat = make_static(stat::Stat::new());
+        let write_crash_report = guc::get_bool(&gucstate, guc::WriteCrashReport);
+        utils::crash::install_panic_hook(stat, write_crash_report);
+        let sample_interval = guc::get_int(&gucstate, guc::WaitEventSampleInterval);
+        if sample_interval > 0 {
+            let sample_interval = Duration::from_millis(sample_interval as u64);
+            stat::start_wait_event_sampler(pm, stat, sample_interval)?;
+        }
+        let tmpdirs = make_static(utils::tmpfile::TempDirs::new(&gucstate));
+        tmpdirs.cleanup()?;
+        let sessid = make_static(AtomicU32::new(1));
+        return Ok(GlobalState {
+            gucstate,
+            urings,
+            pm,
+            notify,
+            stat,
+            tmpdirs,
+            sessid,
+        });
+    }
+
+    fn new_sessid(&self) -> u32 {
+        self.sessid.fetch_add(1, Relaxed)
     }
 }
 
@@ -205,21 +245,47 @@ async fn write_cmd_complete(tag: &str, stream: &mut Sock) {
 async fn on_error(level: &str, err: &anyhow::Error, writer: &mut Sock) {
     let ec = errcode(err);
     let msg = format!("{:#}", err);
-    error!("msglvl={} code={} {}", level, ec, &msg);
+    let errctx = errctx(err);
+    let detail = errctx.and_then(|v| v.detail.as_deref());
+    let hint = errctx.and_then(|v| v.hint.as_deref());
+    let loc = errctx.and_then(|v| v.loc);
+    error!(
+        "msglvl={} code={} loc={:?} detail={:?} hint={:?} {}",
+        level, ec, loc, detail, hint, &msg
+    );
+    let line = loc.map(|v| v.line.to_string());
+    let mut errmsg = protocol::ErrorResponse::new(level, ec, &msg);
+    errmsg.fields.detail = detail;
+    errmsg.fields.hint = hint;
+    errmsg.fields.file = loc.map(|v| v.file);
+    errmsg.fields.line = line.as_deref();
     // ignore error, just as send_message_to_frontend().
-    protocol::write_message(writer, &protocol::ErrorResponse::new(level, ec, &msg)).await;
+    protocol::write_message(writer, &errmsg).await;
     let _ = writer.s.flush().await;
     return;
 }
 
 const NOSSL: [u8; 1] = ['N' as u8];
 
-async fn do_postgres_main(gstate: GlobalState, sock: &mut Sock) -> anyhow::Result<()> {
+async fn do_postgres_main(
+    mut gstate: GlobalState,
+    sock: &mut Sock,
+    srvfd: i32,
+) -> anyhow::Result<()> {
     let mut inmsgbuf = Vec::new();
     protocol::read_startup_message(sock, &mut inmsgbuf).await?;
     if let Some(req) = protocol::CancelRequest::deserialize(&inmsgbuf) {
         trace!("receive CancelRequest. CancelRequest={:?}", req);
-        todo!();
+        // Same as PostgreSQL, nothing is sent back to the client.
+        match gstate.stat.get(req.sess) {
+            Some(backend) if backend.key == req.key => {
+                backend.cancel(stat::CancelReason::UserRequest);
+            }
+            _ => {
+                warn!("invalid CancelRequest. sessid={}", req.sess);
+            }
+        }
+        return Ok(());
     }
     if let Some(_) = protocol::SSLRequest::deserialize(&inmsgbuf) {
         sock.s.write_all(&NOSSL).await?;
@@ -236,11 +302,29 @@ async fn do_postgres_main(gstate: GlobalState, sock: &mut Sock) -> anyhow::Resul
     info!("receive startup message. msg={:?}", &startup);
     let expected_client_encoding = guc::get_str(&gstate.gucstate, guc::ClientEncoding);
     // validate
+    if !startup.check_client_encoding(expected_client_encoding) {
+        return Err(anyhow::anyhow!("").context(
+            errctx!(
+                ERRCODE_PROTOCOL_VIOLATION,
+                "Unsupported client encoding. expected={}",
+                expected_client_encoding
+            )
+            .detail(format!(
+                "The client requested client_encoding {}.",
+                startup.client_encoding().unwrap_or_default()
+            ))
+            .hint(format!(
+                "Set client_encoding to {} in the connection parameters.",
+                expected_client_encoding
+            )),
+        ));
+    }
+    // Reject before AuthenticationOk and register(), so that neither the client nor
+    // wait_sessions() sees a session which is going to fail.
     kbensure!(
-        startup.check_client_encoding(expected_client_encoding),
-        ERRCODE_PROTOCOL_VIOLATION,
-        "Unsupported client encoding. expected={}",
-        expected_client_encoding
+        gstate.pm.shutdown_mode() == postmaster::ShutdownMode::None,
+        ERRCODE_CANNOT_CONNECT_NOW,
+        "the database system is shutting down"
     );
     // post-validate
     // let sesskey = rand::random();
@@ -251,20 +335,36 @@ async fn do_postgres_main(gstate: GlobalState, sock: &mut Sock) -> anyhow::Resul
     // post-validate for client-side
     protocol::write_message(sock, &protocol::AuthenticationOk {}).await;
     protocol::report_all_gucs(&gstate.gucstate, sock).await;
-    protocol::write_message(sock, &protocol::BackendKeyData::new(0, 0 /* todo! */)).await;
+    let sessid = gstate.new_sessid();
+    let backend = gstate.stat.register(sessid, srvfd);
+    protocol::write_message(sock, &protocol::BackendKeyData::new(sessid, backend.key)).await;
+    let mut listener = notify::Listener::new(gstate.notify, sessid);
+    let mut tabstat = stat::table::TabStatPending::default();
     // state.init_thread_locals();
+    let mut gucgen = 0;
     loop {
-        // state.check_termreq()?;
+        backend.check_termreq()?;
+        if gstate.pm.sync_gucstate(&mut gstate.gucstate, &mut gucgen) {
+            info!("apply reloaded config. gen={}", gucgen);
+        }
+        send_notifications(&listener, sock).await;
         protocol::write_message(
             sock,
             &protocol::ReadyForQuery::new(protocol::XactStatus::NotInBlock /* todo!() */),
         )
         .await;
-        sock.s.flush().await?;
-        let msgtype = protocol::read_message(sock, &mut inmsgbuf)
-            .await
-            .with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "read_message failed"))?;
-        // state.check_termreq()?;
+        {
+            let _wait = backend.wait.start(stat::wait::WaitEvent::ClientWrite);
+            sock.s.flush().await?;
+        }
+        let msgtype = {
+            let _wait = backend.wait.start(stat::wait::WaitEvent::ClientRead);
+            wait_client(&listener, sock).await?;
+            protocol::read_message(sock, &mut inmsgbuf).await
+        };
+        backend.check_termreq()?;
+        let msgtype =
+            msgtype.with_context(|| errctx!(ERRCODE_CONNECTION_FAILURE, "read_message failed"))?;
         if msgtype == protocol::MsgType::EOF as i8 || msgtype == protocol::MsgType::Terminate as i8
         {
             info!("end connection");
@@ -285,14 +385,86 @@ async fn do_postgres_main(gstate: GlobalState, sock: &mut Sock) -> anyhow::Resul
             )
         })?;
         info!("receive query. query={:?}", query);
-        // exec_simple_query(query.query, &mut state, sockwriter);
-        write_cmd_complete("HELLOWORLD", sock).await;
+        backend.set_query(query.query);
+        let res = exec_simple_query(&gstate, &backend, query.query, sock).await;
+        backend.set_query("");
+        // There is no transaction block yet, every query is committed right away.
+        if let Err(err) = res {
+            // state.abort_cur_tran();
+            listener.at_abort();
+            on_error(protocol::SEVERITY_ERR, &err, sock).await;
+        } else {
+            listener.at_commit();
+        }
+        tabstat.flush(&gstate.stat.tables);
         // if state.dead {
         //     return Ok(());
         // }
     }
 }
 
+async fn send_notifications(listener: &notify::Listener, sock: &mut Sock) {
+    for notification in listener.drain() {
+        let msg = protocol::NotificationResponse {
+            sessid: notification.sessid,
+            channel: &notification.channel,
+            payload: &notification.payload,
+        };
+        protocol::write_message(sock, &msg).await;
+    }
+    return;
+}
+
+// Wait until the client sends something, the notifications arriving meanwhile are
+// sent to the client right away, same as PostgreSQL does for an idle session.
+async fn wait_client(listener: &notify::Listener, sock: &mut Sock) -> anyhow::Result<()> {
+    loop {
+        // Dropping fill_buf() when the notification wins is only safe because of how
+        // io::Stream reads: poll_read() submits an io_uring recv into the buffer it is
+        // given and keeps it in flight in read_fut, the next poll_read() must pass the same
+        // address and length (see the debug_asserts in io.rs) and picks up its result.
+        // BufReader passes its whole internal buffer while it is empty, so this holds as long
+        // as nothing else reads from sock.s before the next fill_buf(), writing is fine since
+        // it uses the separate write_fut. Any change to the read path must keep this.
+        let readable = tokio::select! {
+            _ = sock.s.fill_buf() => true,
+            _ = listener.notified() => false,
+        };
+        if readable {
+            // read_message() will report the error if there is any.
+            return Ok(());
+        }
+        send_notifications(listener, sock).await;
+        sock.s.flush().await?;
+    }
+}
+
+async fn exec_simple_query(
+    gstate: &GlobalState,
+    backend: &stat::BackendGuard,
+    _query: &str,
+    sock: &mut Sock,
+) -> anyhow::Result<()> {
+    // A CancelRequest arriving while the session is idle should not affect the next statement.
+    backend.reset_cancel();
+    let timeout = guc::get_int(&gstate.gucstate, guc::StatementTimeout);
+    let _timeout = if timeout > 0 {
+        Some(backend.start_stmt_timeout(Duration::from_millis(timeout as u64)))
+    } else {
+        None
+    };
+    // The executor should call backend.check_cancel() in its loops. Until then, check it
+    // after sending the result, which is the only place a statement may wait for long.
+    backend.check_cancel()?;
+    write_cmd_complete("HELLOWORLD", sock).await;
+    {
+        let _wait = backend.wait.start(stat::wait::WaitEvent::ClientWrite);
+        sock.s.flush().await?;
+    }
+    backend.check_cancel()?;
+    return Ok(());
+}
+
 const SOCK_SEND_BUF_SIZE: usize = 8192;
 const SOCK_RECV_BUF_SIZE: usize = 8192;
 
@@ -305,7 +477,7 @@ pub async fn postgres_main(gstate: GlobalState, srvfd: i32, cliaddr: SocketAddr)
         SOCK_SEND_BUF_SIZE,
         Stream::new(uring, srvfd),
     ));
-    let res = do_postgres_main(gstate, &mut stream).await;
+    let res = do_postgres_main(gstate, &mut stream, srvfd).await;
     if let Err(err) = res {
         on_error(protocol::SEVERITY_FATAL, &err, &mut stream).await;
     }
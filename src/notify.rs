@@ -0,0 +1,272 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// LISTEN/NOTIFY. Unlike PostgreSQL, we do not use a SLRU based global queue,
+// every listening session owns a bounded queue and Hub::publish() pushes the
+// notification to all sessions listening on the channel.
+use crate::kbensure;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tracing::warn;
+
+// Same as PostgreSQL, NAMEDATALEN - 1 and NOTIFY_PAYLOAD_MAX_LENGTH.
+const CHANNEL_MAX_LEN: usize = 63;
+const PAYLOAD_MAX_LEN: usize = 8000 - 1;
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub sessid: u32, // the session who sends the notification.
+    pub channel: String,
+    pub payload: String,
+}
+
+struct Inbox {
+    sessid: u32,
+    queue: Mutex<VecDeque<Notification>>,
+    // Wake up the session if it is idle waiting for the client.
+    wakeup: Notify,
+}
+
+pub struct Hub {
+    queue_cap: usize,
+    // channel -> inboxes of the sessions listening on channel.
+    listeners: Mutex<HashMap<String, Vec<Arc<Inbox>>>>,
+}
+
+impl Hub {
+    pub fn new(queue_cap: usize) -> Hub {
+        Hub {
+            queue_cap,
+            listeners: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn publish(&self, notifies: &[Notification]) {
+        let listeners = self.listeners.lock();
+        for notify in notifies {
+            let inboxes = match listeners.get(&notify.channel) {
+                None => continue,
+                Some(v) => v,
+            };
+            for inbox in inboxes {
+                let mut queue = inbox.queue.lock();
+                if queue.len() >= self.queue_cap {
+                    // PostgreSQL will report an error on NOTIFY when the queue is full,
+                    // we prefer to drop the oldest one so that a slow listener does not
+                    // affect others.
+                    let dropped = queue.pop_front();
+                    warn!(
+                        "notify queue is full, drop the oldest notification. sessid={} dropped={:?}",
+                        inbox.sessid, dropped
+                    );
+                }
+                queue.push_back(notify.clone());
+                // If the session is not waiting, the permit is stored and the next
+                // notified() returns immediately, so no wakeup is lost.
+                inbox.wakeup.notify_one();
+            }
+        }
+        return;
+    }
+
+    fn listen(&self, channel: &str, inbox: &Arc<Inbox>) {
+        let mut listeners = self.listeners.lock();
+        let inboxes = listeners.entry(channel.to_string()).or_default();
+        if !inboxes.iter().any(|v| Arc::ptr_eq(v, inbox)) {
+            inboxes.push(inbox.clone());
+        }
+        return;
+    }
+
+    fn unlisten(&self, channel: &str, inbox: &Arc<Inbox>) {
+        let mut listeners = self.listeners.lock();
+        if let Some(inboxes) = listeners.get_mut(channel) {
+            inboxes.retain(|v| !Arc::ptr_eq(v, inbox));
+            if inboxes.is_empty() {
+                listeners.remove(channel);
+            }
+        }
+        return;
+    }
+}
+
+// The per-session state of LISTEN/NOTIFY.
+pub struct Listener {
+    hub: &'static Hub,
+    inbox: Arc<Inbox>,
+    channels: HashSet<String>,
+    // NOTIFY is sent at commit, these are the notifications sent by the current transaction.
+    pending: Vec<Notification>,
+}
+
+fn check_channel(channel: &str) -> anyhow::Result<()> {
+    kbensure!(
+        !channel.is_empty(),
+        ERRCODE_INVALID_PARAMETER_VALUE,
+        "channel name cannot be empty"
+    );
+    kbensure!(
+        channel.len() <= CHANNEL_MAX_LEN,
+        ERRCODE_INVALID_PARAMETER_VALUE,
+        "channel name too long. channel={}",
+        channel
+    );
+    return Ok(());
+}
+
+impl Listener {
+    pub fn new(hub: &'static Hub, sessid: u32) -> Listener {
+        Listener {
+            hub,
+            inbox: Arc::new(Inbox {
+                sessid,
+                queue: Mutex::new(VecDeque::new()),
+                wakeup: Notify::new(),
+            }),
+            channels: HashSet::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn listen(&mut self, channel: &str) -> anyhow::Result<()> {
+        check_channel(channel)?;
+        if self.channels.insert(channel.to_string()) {
+            self.hub.listen(channel, &self.inbox);
+        }
+        return Ok(());
+    }
+
+    pub fn unlisten(&mut self, channel: &str) {
+        if self.channels.remove(channel) {
+            self.hub.unlisten(channel, &self.inbox);
+        }
+        return;
+    }
+
+    pub fn unlisten_all(&mut self) {
+        for channel in self.channels.drain() {
+            self.hub.unlisten(&channel, &self.inbox);
+        }
+        return;
+    }
+
+    pub fn notify(&mut self, channel: &str, payload: &str) -> anyhow::Result<()> {
+        check_channel(channel)?;
+        kbensure!(
+            payload.len() <= PAYLOAD_MAX_LEN,
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "payload string too long. len={}",
+            payload.len()
+        );
+        // Same as PostgreSQL, duplicate notifications in one transaction are folded.
+        if self
+            .pending
+            .iter()
+            .any(|v| v.channel == channel && v.payload == payload)
+        {
+            return Ok(());
+        }
+        self.pending.push(Notification {
+            sessid: self.inbox.sessid,
+            channel: channel.to_string(),
+            payload: payload.to_string(),
+        });
+        return Ok(());
+    }
+
+    pub fn at_commit(&mut self) {
+        if !self.pending.is_empty() {
+            self.hub.publish(&self.pending);
+            self.pending.clear();
+        }
+        return;
+    }
+
+    pub fn at_abort(&mut self) {
+        self.pending.clear();
+    }
+
+    // Return the notifications that should be delivered to the client.
+    pub fn drain(&self) -> Vec<Notification> {
+        self.inbox.queue.lock().drain(..).collect()
+    }
+
+    // Wait until there may be new notifications, wakeups can be spurious so the caller
+    // should drain() after that.
+    pub async fn notified(&self) {
+        self.inbox.wakeup.notified().await
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.unlisten_all();
+    }
+}
+
+#[cfg(test)]
+mod notify_test {
+    use super::{Hub, Listener};
+    use crate::make_static;
+    use std::time::Duration;
+
+    #[test]
+    fn f() {
+        let hub = make_static(Hub::new(2));
+        let mut l1 = Listener::new(hub, 1);
+        let mut l2 = Listener::new(hub, 2);
+        l1.listen("c1").unwrap();
+        l2.notify("c1", "p1").unwrap();
+        l2.notify("c1", "p1").unwrap();
+        l2.notify("c2", "p2").unwrap();
+        assert!(l1.drain().is_empty());
+        l2.at_commit();
+        let notifies = l1.drain();
+        assert_eq!(notifies.len(), 1);
+        assert_eq!(notifies[0].sessid, 2);
+        assert_eq!(notifies[0].payload, "p1");
+
+        l2.notify("c1", "p3").unwrap();
+        l2.at_abort();
+        l2.at_commit();
+        assert!(l1.drain().is_empty());
+
+        // An idle listener is woken up by the commit of another session.
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let notifies = rt.block_on(async {
+            let (notifies, _) = tokio::join!(
+                async {
+                    tokio::time::timeout(Duration::from_secs(10), l1.notified())
+                        .await
+                        .unwrap();
+                    l1.drain()
+                },
+                async {
+                    l2.notify("c1", "p5").unwrap();
+                    l2.at_commit();
+                }
+            );
+            notifies
+        });
+        assert_eq!(notifies.len(), 1);
+        assert_eq!(notifies[0].payload, "p5");
+
+        l1.unlisten("c1");
+        l2.notify("c1", "p4").unwrap();
+        l2.at_commit();
+        assert!(l1.drain().is_empty());
+    }
+}
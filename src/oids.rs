@@ -33,6 +33,7 @@ impl std::convert::From<OptOid> for u32 {
 pub const TEMPLATE0_DB: Oid = unsafe { Oid::new_unchecked(1) };
 pub const KUIBADB: Oid = unsafe { Oid::new_unchecked(2) };
 pub const KBCATLOGNS: Oid = unsafe { Oid::new_unchecked(11) };
+pub const DEFAULTTABLESPACE: Oid = unsafe { Oid::new_unchecked(1663) };
 pub const BOOLOID: Oid = unsafe { Oid::new_unchecked(16) };
 pub const BOOLINPROC: Oid = unsafe { Oid::new_unchecked(1242) };
 pub const BOOLOUTPROC: Oid = unsafe { Oid::new_unchecked(1243) };
@@ -0,0 +1,428 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The postmaster owns the signal handling, the lifecycle of background workers and
+// the config reloading.
+//
+// KuiBaDB is a multi-threaded process, so we block the signals we care about in all threads
+// by calling block_signals() before any thread is created, and a dedicated thread waits for
+// them with sigwait(). So there is no async-signal-safety concern.
+use crate::guc::{self, GucState};
+use crate::stat::Stat;
+use crate::{errctx, kbbail, KB_CONF};
+use anyhow::Context;
+use parking_lot::{const_mutex, Mutex, RwLock};
+use std::os::unix::process::CommandExt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicU8, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PmState {
+    Starting = 0,
+    Running,
+    Stopping,
+    Restarting,
+}
+
+const PM_STATES: [PmState; 4] = [
+    PmState::Starting,
+    PmState::Running,
+    PmState::Stopping,
+    PmState::Restarting,
+];
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd)]
+pub enum ShutdownMode {
+    None = 0,
+    // SIGTERM, wait for all sessions to end.
+    Smart,
+    // SIGINT, terminate all sessions.
+    Fast,
+    // SIGQUIT, exit right away, recovery is needed at next startup.
+    Immediate,
+}
+
+const SHUTDOWN_MODES: [ShutdownMode; 4] = [
+    ShutdownMode::None,
+    ShutdownMode::Smart,
+    ShutdownMode::Fast,
+    ShutdownMode::Immediate,
+];
+
+pub struct Postmaster {
+    state: AtomicU8,
+    shutdown: AtomicU8,
+    listenfd: AtomicI32,
+    gucstate: RwLock<Arc<GucState>>,
+    // Increased every time the config file is reloaded.
+    gucgen: AtomicU64,
+}
+
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const HANDLED_SIGNALS: [i32; 4] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGQUIT];
+
+fn signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut sigset: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut sigset);
+        for &sig in &HANDLED_SIGNALS {
+            libc::sigaddset(&mut sigset, sig);
+        }
+        return sigset;
+    }
+}
+
+// Must be called before any thread is created, so that all threads inherit the signal mask.
+pub fn block_signals() -> std::io::Result<()> {
+    let sigset = signal_set();
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &sigset, std::ptr::null_mut()) };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret));
+    }
+    return Ok(());
+}
+
+impl Postmaster {
+    pub fn new(gucstate: Arc<GucState>) -> Postmaster {
+        Postmaster {
+            state: AtomicU8::new(PmState::Starting as u8),
+            shutdown: AtomicU8::new(ShutdownMode::None as u8),
+            listenfd: AtomicI32::new(-1),
+            gucstate: RwLock::new(gucstate),
+            gucgen: AtomicU64::new(0),
+        }
+    }
+
+    pub fn state(&self) -> PmState {
+        PM_STATES[self.state.load(Relaxed) as usize]
+    }
+
+    pub fn set_state(&self, state: PmState) {
+        let oldstate = self.state();
+        self.state.store(state as u8, Relaxed);
+        info!(
+            "postmaster state changed. from={:?} to={:?}",
+            oldstate, state
+        );
+    }
+
+    pub fn shutdown_mode(&self) -> ShutdownMode {
+        SHUTDOWN_MODES[self.shutdown.load(Relaxed) as usize]
+    }
+
+    // The listen socket will be shut down when a shutdown is requested,
+    // so that the accept loop wakes up.
+    pub fn set_listenfd(&self, fd: i32) {
+        self.listenfd.store(fd, Relaxed);
+    }
+
+    // The latest GucState, sessions should check gucgen() to see if it has been reloaded.
+    pub fn gucstate(&self) -> Arc<GucState> {
+        self.gucstate.read().clone()
+    }
+
+    pub fn gucgen(&self) -> u64 {
+        self.gucgen.load(Relaxed)
+    }
+
+    // Called by sessions before each query, apply the latest config file to gucstate if it
+    // has been reloaded since curgen, the GUCs set by the session are kept.
+    // Return true if gucstate is changed.
+    pub fn sync_gucstate(&self, gucstate: &mut Arc<GucState>, curgen: &mut u64) -> bool {
+        // reload() stores gucstate before increasing gucgen, so the gucstate we read
+        // is at least as new as gen.
+        let gen = self.gucgen();
+        if gen == *curgen {
+            return false;
+        }
+        *gucstate = Arc::new(guc::merge_reloaded(gucstate, &self.gucstate()));
+        *curgen = gen;
+        return true;
+    }
+
+    fn reload(&self) {
+        self.reload_file(KB_CONF);
+    }
+
+    fn reload_file(&self, path: &str) {
+        let newstate = {
+            let curstate = self.gucstate.read();
+            guc::reload(&curstate, path)
+        };
+        match newstate {
+            Ok(newstate) => {
+                *self.gucstate.write() = Arc::new(newstate);
+                let gen = self.gucgen.fetch_add(1, Relaxed) + 1;
+                info!("reload config file. gen={}", gen);
+            }
+            Err(err) => {
+                error!("reload config file failed. err={:#}", err);
+            }
+        }
+        return;
+    }
+
+    fn request_shutdown(&self, mode: ShutdownMode) {
+        // A more urgent mode can override a less urgent one, but not the reverse.
+        if self.shutdown_mode() >= mode {
+            return;
+        }
+        info!("receive shutdown request. mode={:?}", mode);
+        self.shutdown.store(mode as u8, Relaxed);
+        if mode == ShutdownMode::Immediate {
+            std::process::exit(2);
+        }
+        self.set_state(PmState::Stopping);
+        let listenfd = self.listenfd.load(Relaxed);
+        if listenfd >= 0 {
+            unsafe { libc::shutdown(listenfd, libc::SHUT_RDWR) };
+        }
+        return;
+    }
+
+    fn on_signal(&self, sig: i32) {
+        match sig {
+            libc::SIGHUP => self.reload(),
+            libc::SIGTERM => self.request_shutdown(ShutdownMode::Smart),
+            libc::SIGINT => self.request_shutdown(ShutdownMode::Fast),
+            libc::SIGQUIT => self.request_shutdown(ShutdownMode::Immediate),
+            _ => warn!("receive unexpected signal. sig={}", sig),
+        }
+    }
+
+    pub fn start_signal_thread(&'static self) -> std::io::Result<()> {
+        std::thread::Builder::new()
+            .name("signal handler".to_string())
+            .spawn(move || {
+                let sigset = signal_set();
+                loop {
+                    let mut sig = 0;
+                    let ret = unsafe { libc::sigwait(&sigset, &mut sig) };
+                    if ret != 0 {
+                        error!("sigwait failed. err={}", ret);
+                        continue;
+                    }
+                    self.on_signal(sig);
+                }
+            })?;
+        return Ok(());
+    }
+
+    // Start a background worker. If the worker crashes, we restart the whole server,
+    // the crash recovery will be performed during startup.
+    //
+    // NOTE: the release profile sets panic = 'abort', so catch_unwind never returns Err there
+    // and on_worker_crash()/restart() can only run in debug builds. In the release build the
+    // whole process exits right away on panic, and it is the responsibility of the outside
+    // supervisor(systemd etc.) to restart KuiBaDB.
+    pub fn spawn_worker<F>(&'static self, name: &str, f: F) -> std::io::Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let workername = name.to_string();
+        // The workers run until the process exits, so they are detached.
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                if catch_unwind(AssertUnwindSafe(f)).is_err() {
+                    self.on_worker_crash(&workername);
+                } else {
+                    info!("background worker exits. name={}", workername);
+                }
+            })?;
+        return Ok(());
+    }
+
+    // Called after we stop accepting connections, wait for all sessions to exit.
+    // Smart shutdown waits for the clients to disconnect, fast shutdown terminates
+    // the sessions. A smart shutdown can be turned into a fast one by SIGINT.
+    pub fn wait_sessions(&self, stat: &Stat) {
+        let mut terminated = false;
+        loop {
+            let backends = stat.backends();
+            if backends.is_empty() {
+                break;
+            }
+            if !terminated && self.shutdown_mode() >= ShutdownMode::Fast {
+                info!("terminate all sessions. sessions={}", backends.len());
+                for backend in &backends {
+                    backend.terminate();
+                }
+                terminated = true;
+            }
+            std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        info!("all sessions exited. mode={:?}", self.shutdown_mode());
+        return;
+    }
+
+    fn on_worker_crash(&self, name: &str) {
+        error!(
+            "background worker crashed, restart the server. name={}",
+            name
+        );
+        self.set_state(PmState::Restarting);
+        restart();
+    }
+}
+
+// The working directory at startup, init() changes the working directory to datadir.
+static START_DIR: Mutex<Option<PathBuf>> = const_mutex(None);
+
+// Must be called before the working directory is changed.
+pub fn save_start_dir() -> std::io::Result<()> {
+    *START_DIR.lock() = Some(std::env::current_dir()?);
+    return Ok(());
+}
+
+// Re-execute ourselves with the same arguments.
+fn restart() -> ! {
+    let exe = match std::env::current_exe() {
+        Ok(v) => v,
+        Err(err) => {
+            error!("restart failed: current_exe. err={}", err);
+            std::process::abort();
+        }
+    };
+    let mut args = std::env::args_os();
+    args.next();
+    let mut cmd = std::process::Command::new(exe);
+    cmd.args(args);
+    // We have changed the working directory to datadir in init(), go back to the directory
+    // we were started from, in case datadir is a relative path.
+    if let Some(startdir) = START_DIR.lock().as_ref() {
+        cmd.current_dir(startdir);
+    }
+    // Only returns on error.
+    let err = cmd.exec();
+    error!("restart failed: exec. err={}", err);
+    std::process::abort();
+}
+
+// Same as postmaster.pid of PostgreSQL, the pid of the process which owns the data directory.
+// It is held by the server and by the offline tools which modify the data files, such as
+// kb_checksums, so that they can not run at the same time.
+pub const PID_FILE: &str = "kuiba.pid";
+
+// Removes the pid file when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!("remove pid file failed. path={:?} err={}", self.path, err);
+        }
+    }
+}
+
+fn process_alive(pid: i32) -> bool {
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+// Return the pid recorded in the pid file if that process is still alive.
+pub fn datadir_owner(datadir: &Path) -> anyhow::Result<Option<i32>> {
+    let path = datadir.join(PID_FILE);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(v) => v,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).with_context(|| {
+                errctx!(ERRCODE_IO_ERROR, "could not read file. path={:?}", path)
+            });
+        }
+    };
+    // A pid file left by a crash, or a partially written one, is stale.
+    let pid = match content.trim().parse::<i32>() {
+        Ok(v) if v > 0 => v,
+        _ => return Ok(None),
+    };
+    return Ok(if process_alive(pid) { Some(pid) } else { None });
+}
+
+// Take the ownership of datadir, fail if another live process owns it. The pid file written
+// by ourselves before restart() is taken over, since exec keeps the pid.
+pub fn lock_datadir(datadir: &Path) -> anyhow::Result<PidFile> {
+    let path = datadir.join(PID_FILE);
+    let mypid = std::process::id() as i32;
+    match datadir_owner(datadir)? {
+        Some(pid) if pid != mypid => kbbail!(
+            ERRCODE_OBJECT_IN_USE,
+            "the data directory is in use by another process. pid={} pidfile={:?}",
+            pid,
+            path
+        ),
+        _ => {}
+    }
+    let tmppath = datadir.join(format!("{}.{}", PID_FILE, mypid));
+    std::fs::write(&tmppath, format!("{}\n", mypid))
+        .and_then(|_| std::fs::rename(&tmppath, &path))
+        .with_context(|| errctx!(ERRCODE_IO_ERROR, "could not write file. path={:?}", path))?;
+    return Ok(PidFile { path });
+}
+
+#[cfg(test)]
+mod postmaster_test {
+    use super::{datadir_owner, lock_datadir, Postmaster, PID_FILE};
+    use crate::guc::{self, GucState};
+    use crate::protocol::ERRCODE_OBJECT_IN_USE;
+    use crate::utils::err::errcode;
+    use std::sync::Arc;
+
+    #[test]
+    fn f() {
+        let gucstate = Arc::new(GucState::default());
+        let pm = Postmaster::new(gucstate.clone());
+        let mut sessguc = gucstate;
+        let mut gen = 0;
+        assert!(!pm.sync_gucstate(&mut sessguc, &mut gen));
+        guc::set_int_guc(guc::BatchSize, 256, Arc::make_mut(&mut sessguc));
+
+        let path = std::env::temp_dir().join(format!("kbpmtest{}.conf", std::process::id()));
+        std::fs::write(&path, "statement_timeout: 1218\nbatch_size: 512\n").unwrap();
+        pm.reload_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(pm.sync_gucstate(&mut sessguc, &mut gen));
+        assert_eq!(gen, 1);
+        assert_eq!(guc::get_int(&sessguc, guc::StatementTimeout), 1218);
+        // The SET value wins over the config file.
+        assert_eq!(guc::get_int(&sessguc, guc::BatchSize), 256);
+        assert_eq!(guc::get_int(&pm.gucstate(), guc::BatchSize), 512);
+        assert!(!pm.sync_gucstate(&mut sessguc, &mut gen));
+
+        let datadir = std::env::temp_dir().join(format!("kbpmtest{}", std::process::id()));
+        std::fs::create_dir_all(&datadir).unwrap();
+        let pidpath = datadir.join(PID_FILE);
+        // pid 1 is always alive.
+        std::fs::write(&pidpath, "1\n").unwrap();
+        assert_eq!(datadir_owner(&datadir).unwrap(), Some(1));
+        let err = lock_datadir(&datadir).err().unwrap();
+        assert_eq!(errcode(&err), ERRCODE_OBJECT_IN_USE);
+        std::fs::write(&pidpath, "garbage").unwrap();
+        let pidfile = lock_datadir(&datadir).unwrap();
+        assert_eq!(
+            datadir_owner(&datadir).unwrap(),
+            Some(std::process::id() as i32)
+        );
+        drop(pidfile);
+        assert!(!pidpath.exists());
+        std::fs::remove_dir(&datadir).unwrap();
+    }
+}
@@ -177,8 +177,12 @@ impl StartupMessage<'_> {
             .map_or_else(|| self.user(), |v| *v)
     }
 
+    pub(crate) fn client_encoding(&self) -> Option<&str> {
+        self.params.get(&STARTUP_CLIENT_ENCODING).map(|v| *v)
+    }
+
     pub(crate) fn check_client_encoding(&self, expected: &str) -> bool {
-        self.params.get(&STARTUP_CLIENT_ENCODING).map_or(
+        self.client_encoding().map_or(
             true, /* pgbench don't send STARTUP_CLIENT_ENCODING */
             |v| v.eq_ignore_ascii_case(expected),
         )
@@ -191,9 +195,11 @@ pub(crate) struct ErrFields<'a> {
     pub(crate) severity: Option<&'a str>,
     pub(crate) code: Option<&'a str>,
     pub(crate) msg: Option<&'a str>,
+    pub(crate) detail: Option<&'a str>,
+    pub(crate) hint: Option<&'a str>,
+    pub(crate) file: Option<&'a str>,
+    pub(crate) line: Option<&'a str>,
     // pub(crate) V: Option<&'a str>,
-    // pub(crate) D: Option<&'a str>,
-    // pub(crate) H: Option<&'a str>,
     // pub(crate) P: Option<&'a str>,
     // pub(crate) p: Option<&'a str>,
     // pub(crate) q: Option<&'a str>,
@@ -203,8 +209,6 @@ pub(crate) struct ErrFields<'a> {
     // pub(crate) c: Option<&'a str>,
     // pub(crate) d: Option<&'a str>,
     // pub(crate) n: Option<&'a str>,
-    // pub(crate) F: Option<&'a str>,
-    // pub(crate) L: Option<&'a str>,
     // pub(crate) R: Option<&'a str>,
 }
 
@@ -223,9 +227,9 @@ fn serialize_errmsg(typ: u8, fields: &ErrFields, out: &mut Vec<u8>) {
     write_field!(severity, 'S');
     write_field!(code, 'C');
     write_field!(msg, 'M');
+    write_field!(detail, 'D');
+    write_field!(hint, 'H');
     // write_field!(V, 'V');
-    // write_field!(D, 'D');
-    // write_field!(H, 'H');
     // write_field!(P, 'P');
     // write_field!(p, 'p');
     // write_field!(q, 'q');
@@ -235,8 +239,8 @@ fn serialize_errmsg(typ: u8, fields: &ErrFields, out: &mut Vec<u8>) {
     // write_field!(c, 'c');
     // write_field!(d, 'd');
     // write_field!(n, 'n');
-    // write_field!(F, 'F');
-    // write_field!(L, 'L');
+    write_field!(file, 'F');
+    write_field!(line, 'L');
     // write_field!(R, 'R');
     out.push(0);
     let msglen = out.len() - 1;
@@ -517,3 +521,23 @@ impl Message for DataRow<'_, '_> {
         return;
     }
 }
+
+pub(crate) struct NotificationResponse<'a> {
+    pub(crate) sessid: u32,
+    pub(crate) channel: &'a str,
+    pub(crate) payload: &'a str,
+}
+
+impl Message for NotificationResponse<'_> {
+    fn serialize(&self, buff: &mut Vec<u8>) {
+        buff.reserve(64);
+        buff.clear();
+        buff.resize(5, 'A' as u8);
+        ser::ser_be_u32(buff, self.sessid);
+        ser::ser_cstr(buff, self.channel);
+        ser::ser_cstr(buff, self.payload);
+        let msglen = buff.len() - 1;
+        ser::ser_be_u32_at(buff, 1, msglen as u32);
+        return;
+    }
+}
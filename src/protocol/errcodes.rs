@@ -14,6 +14,7 @@ pub const ERRCODE_UNDEFINED_DATABASE: &str = "3D000";
 pub const ERRCODE_CONNECTION_FAILURE: &str = "08006";
 pub const ERRCODE_PROTOCOL_VIOLATION: &str = "08P01";
 pub const ERRCODE_ADMIN_SHUTDOWN: &str = "57P01";
+pub const ERRCODE_CANNOT_CONNECT_NOW: &str = "57P03";
 pub const ERRCODE_SYNTAX_ERROR: &str = "42601";
 pub const ERRCODE_INTERNAL_ERROR: &str = "XX000";
 pub const ERRCODE_FEATURE_NOT_SUPPORTED: &str = "0A000";
@@ -23,9 +24,18 @@ pub const ERRCODE_INVALID_PARAMETER_VALUE: &str = "22023";
 pub const ERRCODE_UNDEFINED_FUNCTION: &str = "42883";
 pub const ERRCODE_NUMERIC_VALUE_OUT_OF_RANGE: &str = "22003";
 pub const ERRCODE_DIVISION_BY_ZERO: &str = "22012";
+pub const ERRCODE_DATATYPE_MISMATCH: &str = "42804";
 pub const ERRCODE_IN_FAILED_SQL_TRANSACTION: &str = "25P02";
 pub const ERRCODE_ACTIVE_SQL_TRANSACTION: &str = "25001";
 pub const ERRCODE_NO_ACTIVE_SQL_TRANSACTION: &str = "25P01";
 pub const ERRCODE_UNDEFINED_TABLE: &str = "42P01";
 pub const ERRCODE_BAD_COPY_FILE_FORMAT: &str = "22P04";
+pub const ERRCODE_CHARACTER_NOT_IN_REPERTOIRE: &str = "22021";
 pub const ERRCODE_NOT_NULL_VIOLATION: &str = "23502";
+pub const ERRCODE_OUT_OF_MEMORY: &str = "53200";
+pub const ERRCODE_QUERY_CANCELED: &str = "57014";
+pub const ERRCODE_DATA_CORRUPTED: &str = "XX001";
+pub const ERRCODE_IO_ERROR: &str = "58030";
+pub const ERRCODE_CONFIGURATION_LIMIT_EXCEEDED: &str = "53400";
+pub const ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE: &str = "55000";
+pub const ERRCODE_OBJECT_IN_USE: &str = "55006";
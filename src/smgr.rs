@@ -0,0 +1,541 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The storage manager. The buffer layer, the WAL redo and the backup address the
+// relation files through Smgr by (relation, fork, block) instead of raw paths,
+// so that other backends, such as an object store, can be plugged in later.
+//
+// Every block starts with a page header of PAGE_HEADER_SIZE bytes holding the checksum of
+// the block, upper layers must not use these bytes. The header is reserved whether or not
+// checksums are enabled, so enabling them never overwrites user data. If they are enabled,
+// the checksum is set when the block is written and verified when the block is read.
+//
+// There is no control file yet, so whether checksums are enabled is recorded in
+// the kb_data_checksums file of the data directory, see kb_checksums.
+use crate::oids::{Oid, DEFAULTTABLESPACE};
+use crate::{errctx, kbbail, kbensure};
+use anyhow::Context;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const BLCKSZ: usize = 8192;
+pub const BASE_DIR: &str = "base";
+// kb_tblspc/<spcoid> is a symlink to the location of the tablespace.
+pub const TBLSPC_DIR: &str = "kb_tblspc";
+// pd_checksum is the only field of the page header for now.
+pub const PAGE_HEADER_SIZE: usize = 4;
+const DATA_CHECKSUMS_FILE: &str = "kb_data_checksums";
+const DATA_CHECKSUMS_ON: &str = "on";
+const DATA_CHECKSUMS_OFF: &str = "off";
+
+pub fn data_checksums_enabled(datadir: &Path) -> anyhow::Result<bool> {
+    let path = datadir.join(DATA_CHECKSUMS_FILE);
+    let state = match std::fs::read_to_string(&path) {
+        Ok(v) => v,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => {
+            return Err(err).with_context(|| {
+                errctx!(ERRCODE_IO_ERROR, "could not read file. path={:?}", path)
+            });
+        }
+    };
+    match state.trim() {
+        DATA_CHECKSUMS_ON => Ok(true),
+        DATA_CHECKSUMS_OFF => Ok(false),
+        _ => kbbail!(
+            ERRCODE_DATA_CORRUPTED,
+            "invalid data checksums state. path={:?} state={:?}",
+            path,
+            state
+        ),
+    }
+}
+
+// Must only be called on a stopped cluster, the file is replaced atomically.
+pub fn set_data_checksums(datadir: &Path, enabled: bool) -> anyhow::Result<()> {
+    let path = datadir.join(DATA_CHECKSUMS_FILE);
+    let tmppath = datadir.join(format!("{}.tmp", DATA_CHECKSUMS_FILE));
+    let state = if enabled {
+        DATA_CHECKSUMS_ON
+    } else {
+        DATA_CHECKSUMS_OFF
+    };
+    let file = File::create(&tmppath).with_context(|| {
+        errctx!(
+            ERRCODE_IO_ERROR,
+            "could not create file. path={:?}",
+            tmppath
+        )
+    })?;
+    file.write_all_at(state.as_bytes(), 0)
+        .and_then(|_| file.sync_all())
+        .and_then(|_| std::fs::rename(&tmppath, &path))
+        .with_context(|| errctx!(ERRCODE_IO_ERROR, "could not write file. path={:?}", path))?;
+    return Ok(());
+}
+
+// FNV-1a over the block except the page header, mixed with the block number,
+// so that a block written to the wrong location is detected too.
+pub fn block_checksum(buf: &[u8], blkno: u32) -> u32 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    let mut hash = FNV_OFFSET;
+    for &b in &buf[PAGE_HEADER_SIZE..BLCKSZ] {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash ^= blkno as u64;
+    hash = hash.wrapping_mul(FNV_PRIME);
+    return (hash ^ (hash >> 32)) as u32;
+}
+
+pub fn set_checksum(buf: &mut [u8], blkno: u32) {
+    let checksum = block_checksum(buf, blkno);
+    buf[..PAGE_HEADER_SIZE].copy_from_slice(&checksum.to_le_bytes());
+}
+
+// An all-zero block is valid, it may be left by a crash during extending.
+pub fn verify_checksum(buf: &[u8], blkno: u32) -> bool {
+    let stored = u32::from_le_bytes(buf[..PAGE_HEADER_SIZE].try_into().unwrap());
+    stored == block_checksum(buf, blkno) || buf.iter().all(|&v| v == 0)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RelFileNode {
+    pub spc: Oid,
+    pub db: Oid,
+    pub rel: Oid,
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Fork {
+    Main = 0,
+    Fsm,
+    Vm,
+    Init,
+}
+
+const FORKS: [Fork; 4] = [Fork::Main, Fork::Fsm, Fork::Vm, Fork::Init];
+
+impl Fork {
+    fn suffix(self) -> &'static str {
+        match self {
+            Fork::Main => "",
+            Fork::Fsm => "_fsm",
+            Fork::Vm => "_vm",
+            Fork::Init => "_init",
+        }
+    }
+}
+
+pub trait Smgr: Send + Sync {
+    fn create(&self, rel: RelFileNode, fork: Fork) -> anyhow::Result<()>;
+    fn exists(&self, rel: RelFileNode, fork: Fork) -> bool;
+    fn nblocks(&self, rel: RelFileNode, fork: Fork) -> anyhow::Result<u32>;
+    // Add a new block, blkno must be nblocks().
+    fn extend(&self, rel: RelFileNode, fork: Fork, blkno: u32, buf: &[u8]) -> anyhow::Result<()>;
+    fn read(&self, rel: RelFileNode, fork: Fork, blkno: u32, buf: &mut [u8]) -> anyhow::Result<()>;
+    fn write(&self, rel: RelFileNode, fork: Fork, blkno: u32, buf: &[u8]) -> anyhow::Result<()>;
+    // Remove all forks of the relation.
+    fn unlink(&self, rel: RelFileNode) -> anyhow::Result<()>;
+    fn sync(&self, rel: RelFileNode, fork: Fork) -> anyhow::Result<()>;
+}
+
+type FileKey = (RelFileNode, Fork);
+
+// The opened files, at most max_files of them are kept, the least recently used one
+// is closed when the limit is reached. A file in use by others is closed after they
+// drop their Arc.
+struct OpenFiles {
+    max_files: usize,
+    clock: u64,
+    files: HashMap<FileKey, (Arc<File>, u64 /* last used */)>,
+}
+
+impl OpenFiles {
+    fn get(&mut self, key: &FileKey) -> Option<Arc<File>> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.files.get_mut(key).map(|v| {
+            v.1 = clock;
+            v.0.clone()
+        })
+    }
+
+    fn insert(&mut self, key: FileKey, file: Arc<File>) {
+        if self.files.len() >= self.max_files {
+            let victim = self.files.iter().min_by_key(|(_, v)| v.1).map(|(k, _)| *k);
+            if let Some(victim) = victim {
+                self.files.remove(&victim);
+            }
+        }
+        self.clock += 1;
+        self.files.insert(key, (file, self.clock));
+    }
+}
+
+// The relation files are stored in `<root>/base/<db>/<rel><fork suffix>` for the default
+// tablespace, and in `<root>/kb_tblspc/<spc>/<db>/<rel><fork suffix>` for others.
+pub struct LocalSmgr {
+    root: PathBuf,
+    checksums: bool,
+    files: Mutex<OpenFiles>,
+}
+
+impl LocalSmgr {
+    // max_files is usually max_files_per_process.
+    pub fn new(root: PathBuf, max_files: usize) -> anyhow::Result<LocalSmgr> {
+        kbensure!(
+            max_files > 0,
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "max_files must be positive. max_files={}",
+            max_files
+        );
+        let checksums = data_checksums_enabled(&root)?;
+        return Ok(LocalSmgr {
+            root,
+            checksums,
+            files: Mutex::new(OpenFiles {
+                max_files,
+                clock: 0,
+                files: HashMap::new(),
+            }),
+        });
+    }
+
+    fn spc_path(&self, spc: Oid) -> PathBuf {
+        if spc == DEFAULTTABLESPACE {
+            self.root.join(BASE_DIR)
+        } else {
+            self.root.join(TBLSPC_DIR).join(spc.to_string())
+        }
+    }
+
+    fn path(&self, rel: RelFileNode, fork: Fork) -> PathBuf {
+        self.spc_path(rel.spc)
+            .join(rel.db.to_string())
+            .join(format!("{}{}", rel.rel, fork.suffix()))
+    }
+
+    // Same as PostgreSQL, location must be an existing empty directory given by absolute path.
+    pub fn create_tablespace(&self, spc: Oid, location: &Path) -> anyhow::Result<()> {
+        kbensure!(
+            location.is_absolute(),
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "tablespace location must be an absolute path. location={:?}",
+            location
+        );
+        let mut entries = std::fs::read_dir(location).with_context(|| {
+            errctx!(
+                ERRCODE_INVALID_PARAMETER_VALUE,
+                "could not open tablespace location. location={:?}",
+                location
+            )
+        })?;
+        kbensure!(
+            entries.next().is_none(),
+            ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+            "tablespace location is not empty. location={:?}",
+            location
+        );
+        let tblspc_dir = self.root.join(TBLSPC_DIR);
+        std::fs::create_dir_all(&tblspc_dir).with_context(|| {
+            errctx!(
+                ERRCODE_IO_ERROR,
+                "could not create directory. dir={:?}",
+                tblspc_dir
+            )
+        })?;
+        let linkpath = self.spc_path(spc);
+        std::os::unix::fs::symlink(location, &linkpath).with_context(|| {
+            errctx!(
+                ERRCODE_IO_ERROR,
+                "could not create symbolic link. link={:?} location={:?}",
+                linkpath,
+                location
+            )
+        })?;
+        return Ok(());
+    }
+
+    // All relations in the tablespace must have been unlinked, the empty database
+    // directories are removed.
+    pub fn drop_tablespace(&self, spc: Oid) -> anyhow::Result<()> {
+        kbensure!(
+            spc != DEFAULTTABLESPACE,
+            ERRCODE_INVALID_PARAMETER_VALUE,
+            "cannot drop the default tablespace"
+        );
+        let linkpath = self.spc_path(spc);
+        let entries = std::fs::read_dir(&linkpath).with_context(|| {
+            errctx!(
+                ERRCODE_IO_ERROR,
+                "could not open tablespace directory. dir={:?}",
+                linkpath
+            )
+        })?;
+        for entry in entries {
+            let dbdir = entry?.path();
+            if std::fs::remove_dir(&dbdir).is_err() {
+                kbbail!(
+                    ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+                    "tablespace is not empty. spc={} dir={:?}",
+                    spc,
+                    dbdir
+                );
+            }
+        }
+        std::fs::remove_file(&linkpath).with_context(|| {
+            errctx!(
+                ERRCODE_IO_ERROR,
+                "could not remove symbolic link. link={:?}",
+                linkpath
+            )
+        })?;
+        return Ok(());
+    }
+
+    fn open(&self, rel: RelFileNode, fork: Fork) -> anyhow::Result<Arc<File>> {
+        let mut files = self.files.lock();
+        if let Some(file) = files.get(&(rel, fork)) {
+            return Ok(file);
+        }
+        let path = self.path(rel, fork);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| errctx!(ERRCODE_IO_ERROR, "could not open file. path={:?}", path))?;
+        let file = Arc::new(file);
+        files.insert((rel, fork), file.clone());
+        return Ok(file);
+    }
+}
+
+impl Smgr for LocalSmgr {
+    fn create(&self, rel: RelFileNode, fork: Fork) -> anyhow::Result<()> {
+        let path = self.path(rel, fork);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| {
+                errctx!(
+                    ERRCODE_IO_ERROR,
+                    "could not create directory. dir={:?}",
+                    dir
+                )
+            })?;
+        }
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| errctx!(ERRCODE_IO_ERROR, "could not create file. path={:?}", path))?;
+        return Ok(());
+    }
+
+    fn exists(&self, rel: RelFileNode, fork: Fork) -> bool {
+        self.files.lock().files.contains_key(&(rel, fork)) || self.path(rel, fork).exists()
+    }
+
+    fn nblocks(&self, rel: RelFileNode, fork: Fork) -> anyhow::Result<u32> {
+        let file = self.open(rel, fork)?;
+        let len = file.metadata().with_context(|| {
+            errctx!(
+                ERRCODE_IO_ERROR,
+                "could not stat file. rel={:?} fork={:?}",
+                rel,
+                fork
+            )
+        })?;
+        return Ok((len.len() / BLCKSZ as u64) as u32);
+    }
+
+    fn extend(&self, rel: RelFileNode, fork: Fork, blkno: u32, buf: &[u8]) -> anyhow::Result<()> {
+        let nblocks = self.nblocks(rel, fork)?;
+        kbensure!(
+            blkno == nblocks,
+            ERRCODE_INTERNAL_ERROR,
+            "extend at unexpected block. rel={:?} fork={:?} blkno={} nblocks={}",
+            rel,
+            fork,
+            blkno,
+            nblocks
+        );
+        return self.write(rel, fork, blkno, buf);
+    }
+
+    fn read(&self, rel: RelFileNode, fork: Fork, blkno: u32, buf: &mut [u8]) -> anyhow::Result<()> {
+        kbensure!(
+            buf.len() == BLCKSZ,
+            ERRCODE_INTERNAL_ERROR,
+            "invalid buffer size. rel={:?} fork={:?} blkno={} len={}",
+            rel,
+            fork,
+            blkno,
+            buf.len()
+        );
+        let file = self.open(rel, fork)?;
+        file.read_exact_at(buf, blkno as u64 * BLCKSZ as u64)
+            .with_context(|| {
+                errctx!(
+                    ERRCODE_IO_ERROR,
+                    "could not read block. rel={:?} fork={:?} blkno={}",
+                    rel,
+                    fork,
+                    blkno
+                )
+            })?;
+        kbensure!(
+            !self.checksums || verify_checksum(buf, blkno),
+            ERRCODE_DATA_CORRUPTED,
+            "invalid page checksum. rel={:?} fork={:?} blkno={}",
+            rel,
+            fork,
+            blkno
+        );
+        return Ok(());
+    }
+
+    fn write(&self, rel: RelFileNode, fork: Fork, blkno: u32, buf: &[u8]) -> anyhow::Result<()> {
+        kbensure!(
+            buf.len() == BLCKSZ,
+            ERRCODE_INTERNAL_ERROR,
+            "invalid buffer size. rel={:?} fork={:?} blkno={} len={}",
+            rel,
+            fork,
+            blkno,
+            buf.len()
+        );
+        let file = self.open(rel, fork)?;
+        // Same as PageSetChecksumCopy(), the caller's buffer is left untouched.
+        let mut blk;
+        let buf = if self.checksums {
+            blk = buf.to_vec();
+            set_checksum(&mut blk, blkno);
+            &blk[..]
+        } else {
+            buf
+        };
+        file.write_all_at(buf, blkno as u64 * BLCKSZ as u64)
+            .with_context(|| {
+                errctx!(
+                    ERRCODE_IO_ERROR,
+                    "could not write block. rel={:?} fork={:?} blkno={}",
+                    rel,
+                    fork,
+                    blkno
+                )
+            })?;
+        return Ok(());
+    }
+
+    fn unlink(&self, rel: RelFileNode) -> anyhow::Result<()> {
+        let mut files = self.files.lock();
+        for &fork in &FORKS {
+            files.files.remove(&(rel, fork));
+            let path = self.path(rel, fork);
+            match std::fs::remove_file(&path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(err).with_context(|| {
+                        errctx!(ERRCODE_IO_ERROR, "could not remove file. path={:?}", path)
+                    });
+                }
+                _ => {}
+            }
+        }
+        return Ok(());
+    }
+
+    fn sync(&self, rel: RelFileNode, fork: Fork) -> anyhow::Result<()> {
+        let file = self.open(rel, fork)?;
+        file.sync_data().with_context(|| {
+            errctx!(
+                ERRCODE_IO_ERROR,
+                "could not fsync file. rel={:?} fork={:?}",
+                rel,
+                fork
+            )
+        })?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod smgr_test {
+    use super::{set_data_checksums, Fork, LocalSmgr, RelFileNode, Smgr, BLCKSZ, PAGE_HEADER_SIZE};
+    use crate::oids::{Oid, DEFAULTTABLESPACE};
+    use crate::protocol::{ERRCODE_DATA_CORRUPTED, ERRCODE_INTERNAL_ERROR};
+    use crate::utils::err::errcode;
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn f() {
+        let root = std::env::temp_dir().join(format!("kbsmgrtest{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        set_data_checksums(&root, true).unwrap();
+        assert!(LocalSmgr::new(root.clone(), 0).is_err());
+        let smgr = LocalSmgr::new(root.clone(), 1).unwrap();
+        let rel = RelFileNode {
+            spc: DEFAULTTABLESPACE,
+            db: Oid::new(2).unwrap(),
+            rel: Oid::new(16384).unwrap(),
+        };
+        assert!(!smgr.exists(rel, Fork::Main));
+        smgr.create(rel, Fork::Main).unwrap();
+        assert!(smgr.exists(rel, Fork::Main));
+        assert_eq!(smgr.nblocks(rel, Fork::Main).unwrap(), 0);
+        let blk = vec![7u8; BLCKSZ];
+        smgr.extend(rel, Fork::Main, 0, &blk).unwrap();
+        assert!(smgr.extend(rel, Fork::Main, 2, &blk).is_err());
+        let err = smgr.write(rel, Fork::Main, 0, &blk[1..]).unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_INTERNAL_ERROR);
+        assert_eq!(smgr.nblocks(rel, Fork::Main).unwrap(), 1);
+        let mut buf = vec![0u8; BLCKSZ];
+        smgr.read(rel, Fork::Main, 0, &mut buf).unwrap();
+        assert_eq!(buf[PAGE_HEADER_SIZE..], blk[PAGE_HEADER_SIZE..]);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(root.join("base").join("2").join("16384"))
+            .unwrap();
+        file.write_all_at(&[8u8], 33).unwrap();
+        let err = smgr.read(rel, Fork::Main, 0, &mut buf).unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_DATA_CORRUPTED);
+        smgr.sync(rel, Fork::Main).unwrap();
+        // Opening another fork closes the main fork, which is reopened on the next access.
+        smgr.create(rel, Fork::Fsm).unwrap();
+        assert_eq!(smgr.nblocks(rel, Fork::Fsm).unwrap(), 0);
+        assert_eq!(smgr.files.lock().files.len(), 1);
+        assert!(smgr.files.lock().files.contains_key(&(rel, Fork::Fsm)));
+        assert_eq!(smgr.nblocks(rel, Fork::Main).unwrap(), 1);
+        assert!(smgr.files.lock().files.contains_key(&(rel, Fork::Main)));
+        smgr.unlink(rel).unwrap();
+        assert!(!smgr.exists(rel, Fork::Fsm));
+        assert!(!smgr.exists(rel, Fork::Main));
+
+        let location = root.join("spc");
+        std::fs::create_dir_all(&location).unwrap();
+        let spc = Oid::new(16385).unwrap();
+        smgr.create_tablespace(spc, &location).unwrap();
+        let rel = RelFileNode { spc, ..rel };
+        smgr.create(rel, Fork::Main).unwrap();
+        assert!(location.join("2").join("16384").exists());
+        assert!(smgr.drop_tablespace(spc).is_err());
+        smgr.unlink(rel).unwrap();
+        smgr.drop_tablespace(spc).unwrap();
+        assert!(!smgr.exists(rel, Fork::Main));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
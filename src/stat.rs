@@ -0,0 +1,246 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::postmaster::Postmaster;
+use crate::{kbanyhow, kbensure};
+use parking_lot::Mutex;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+pub mod table;
+pub mod wait;
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CancelReason {
+    None = 0,
+    UserRequest,
+    StatementTimeout,
+}
+
+const CANCEL_REASONS: [CancelReason; 3] = [
+    CancelReason::None,
+    CancelReason::UserRequest,
+    CancelReason::StatementTimeout,
+];
+
+// The status of one session, it is shared with the stats collector.
+pub struct Backend {
+    pub sessid: u32,
+    // The secret key sent in BackendKeyData, CancelRequest must carry it.
+    pub key: u32,
+    sockfd: i32,
+    pub wait: wait::WaitState,
+    // The query being executed, empty if the session is idle.
+    pub query: Mutex<String>,
+    termreq: AtomicBool,
+    // The cancellation token of the current statement, long-running operations
+    // should call check_cancel() periodically.
+    cancelreq: AtomicU8,
+}
+
+impl Backend {
+    pub fn set_query(&self, query: &str) {
+        let mut curquery = self.query.lock();
+        curquery.clear();
+        curquery.push_str(query);
+    }
+
+    // Ask the session to exit. The read side of the client socket is shut down,
+    // so that the session blocked in reading wakes up, and the write side is still
+    // available for sending the FATAL message.
+    pub fn terminate(&self) {
+        self.termreq.store(true, Relaxed);
+        unsafe { libc::shutdown(self.sockfd, libc::SHUT_RD) };
+    }
+
+    pub fn cancel(&self, reason: CancelReason) {
+        self.cancelreq.store(reason as u8, Relaxed);
+    }
+
+    pub fn reset_cancel(&self) {
+        self.cancelreq.store(CancelReason::None as u8, Relaxed);
+    }
+
+    pub fn check_cancel(&self) -> anyhow::Result<()> {
+        match CANCEL_REASONS[self.cancelreq.load(Relaxed) as usize] {
+            CancelReason::None => Ok(()),
+            CancelReason::UserRequest => Err(kbanyhow!(
+                ERRCODE_QUERY_CANCELED,
+                "canceling statement due to user request"
+            )),
+            CancelReason::StatementTimeout => Err(kbanyhow!(
+                ERRCODE_QUERY_CANCELED,
+                "canceling statement due to statement timeout"
+            )),
+        }
+    }
+
+    pub fn check_termreq(&self) -> anyhow::Result<()> {
+        kbensure!(
+            !self.termreq.load(Relaxed),
+            ERRCODE_ADMIN_SHUTDOWN,
+            "terminating connection due to administrator command"
+        );
+        return Ok(());
+    }
+}
+
+pub struct Stat {
+    backends: Mutex<HashMap<u32, Arc<Backend>>>,
+    wait_samples: wait::WaitSamples,
+    pub tables: table::TabStats,
+}
+
+impl Stat {
+    pub fn new() -> Stat {
+        Stat {
+            backends: Mutex::new(HashMap::new()),
+            wait_samples: wait::WaitSamples::default(),
+            tables: table::TabStats::default(),
+        }
+    }
+
+    pub fn register(&'static self, sessid: u32, sockfd: i32) -> BackendGuard {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(sessid);
+        let backend = Arc::new(Backend {
+            sessid,
+            key: hasher.finish() as u32,
+            sockfd,
+            wait: wait::WaitState::default(),
+            query: Mutex::new(String::new()),
+            termreq: AtomicBool::new(false),
+            cancelreq: AtomicU8::new(CancelReason::None as u8),
+        });
+        self.backends.lock().insert(sessid, backend.clone());
+        BackendGuard {
+            stat: self,
+            backend,
+        }
+    }
+
+    pub fn get(&self, sessid: u32) -> Option<Arc<Backend>> {
+        self.backends.lock().get(&sessid).cloned()
+    }
+
+    pub fn backends(&self) -> Vec<Arc<Backend>> {
+        self.backends.lock().values().cloned().collect()
+    }
+
+    // Used by the panic hook, the panicking thread may hold the lock.
+    pub fn try_backends(&self) -> Option<Vec<Arc<Backend>>> {
+        self.backends
+            .try_lock()
+            .map(|v| v.values().cloned().collect())
+    }
+
+    // Sample the wait event of all sessions once.
+    pub fn sample_wait_events(&self) {
+        for backend in self.backends() {
+            self.wait_samples.add(backend.wait.current());
+        }
+        return;
+    }
+
+    pub fn wait_samples(&self) -> Vec<(wait::WaitEvent, u64)> {
+        self.wait_samples.snapshot()
+    }
+}
+
+// Start a thread sampling the wait events of all sessions every `interval`.
+pub fn start_wait_event_sampler(
+    pm: &'static Postmaster,
+    stat: &'static Stat,
+    interval: Duration,
+) -> std::io::Result<()> {
+    const REPORT_LOOPS: u32 = 1024;
+    pm.spawn_worker("wait event sampler", move || {
+        let mut loops = 0u32;
+        loop {
+            std::thread::sleep(interval);
+            stat.sample_wait_events();
+            loops = loops.wrapping_add(1);
+            if loops % REPORT_LOOPS == 0 {
+                info!("wait event samples. samples={:?}", stat.wait_samples());
+            }
+        }
+    })
+}
+
+pub struct BackendGuard {
+    stat: &'static Stat,
+    backend: Arc<Backend>,
+}
+
+impl std::ops::Deref for BackendGuard {
+    type Target = Backend;
+    fn deref(&self) -> &Self::Target {
+        &self.backend
+    }
+}
+
+impl BackendGuard {
+    // Cancel the current statement after `timeout`, the timer is stopped when
+    // the returned StmtTimeout is dropped.
+    pub fn start_stmt_timeout(&self, timeout: Duration) -> StmtTimeout {
+        let backend = self.backend.clone();
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            backend.cancel(CancelReason::StatementTimeout);
+        });
+        StmtTimeout { timer }
+    }
+}
+
+pub struct StmtTimeout {
+    timer: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for StmtTimeout {
+    fn drop(&mut self) {
+        self.timer.abort();
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.stat.backends.lock().remove(&self.backend.sessid);
+    }
+}
+
+#[cfg(test)]
+mod stat_test {
+    use super::Stat;
+    use std::time::Duration;
+
+    #[test]
+    fn f() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let stat: &'static Stat = Box::leak(Box::new(Stat::new()));
+        rt.block_on(async {
+            let backend = stat.register(1, -1);
+            let _timeout = backend.start_stmt_timeout(Duration::from_millis(10));
+            assert!(backend.check_cancel().is_ok());
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(backend.check_cancel().is_err());
+            backend.reset_cancel();
+            assert!(backend.check_cancel().is_ok());
+        });
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::Oid;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TabCounters {
+    pub seq_scan: u64,
+    pub tup_read: u64,
+    pub tup_ins: u64,
+    pub tup_upd: u64,
+    pub tup_del: u64,
+}
+
+impl TabCounters {
+    fn add(&mut self, other: &TabCounters) {
+        self.seq_scan += other.seq_scan;
+        self.tup_read += other.tup_read;
+        self.tup_ins += other.tup_ins;
+        self.tup_upd += other.tup_upd;
+        self.tup_del += other.tup_del;
+    }
+}
+
+// One row of kb_stat_user_tables.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TabStat {
+    pub counters: TabCounters,
+    // The number of rows updated or deleted since the last vacuum,
+    // autovacuum uses it to decide whether the table should be vacuumed.
+    pub n_dead_tup: u64,
+    pub vacuum_count: u64,
+    pub last_vacuum: Option<SystemTime>,
+}
+
+#[derive(Default)]
+pub struct TabStats {
+    tabs: Mutex<HashMap<Oid, TabStat>>,
+}
+
+impl TabStats {
+    fn merge(&self, pending: &HashMap<Oid, TabCounters>) {
+        let mut tabs = self.tabs.lock();
+        for (&reloid, counters) in pending {
+            let tabstat = tabs.entry(reloid).or_default();
+            tabstat.counters.add(counters);
+            tabstat.n_dead_tup += counters.tup_upd + counters.tup_del;
+        }
+        return;
+    }
+
+    pub fn report_vacuum(&self, reloid: Oid) {
+        let mut tabs = self.tabs.lock();
+        let tabstat = tabs.entry(reloid).or_default();
+        tabstat.n_dead_tup = 0;
+        tabstat.vacuum_count += 1;
+        tabstat.last_vacuum = Some(SystemTime::now());
+    }
+
+    pub fn report_drop(&self, reloid: Oid) {
+        self.tabs.lock().remove(&reloid);
+    }
+
+    pub fn get(&self, reloid: Oid) -> Option<TabStat> {
+        self.tabs.lock().get(&reloid).copied()
+    }
+
+    // The content of kb_stat_user_tables, ordered by the oid of table.
+    pub fn user_tables(&self) -> Vec<(Oid, TabStat)> {
+        let mut ret: Vec<_> = self.tabs.lock().iter().map(|(&k, &v)| (k, v)).collect();
+        ret.sort_by_key(|v| v.0);
+        return ret;
+    }
+}
+
+// The counters collected by one session, they are merged into TabStats at the end of transaction,
+// so that we do not need to take the lock of TabStats for every row.
+#[derive(Default)]
+pub struct TabStatPending {
+    tabs: HashMap<Oid, TabCounters>,
+}
+
+impl TabStatPending {
+    fn get(&mut self, reloid: Oid) -> &mut TabCounters {
+        self.tabs.entry(reloid).or_default()
+    }
+
+    pub fn count_seq_scan(&mut self, reloid: Oid) {
+        self.get(reloid).seq_scan += 1;
+    }
+
+    pub fn count_read(&mut self, reloid: Oid, rows: u64) {
+        self.get(reloid).tup_read += rows;
+    }
+
+    pub fn count_insert(&mut self, reloid: Oid, rows: u64) {
+        self.get(reloid).tup_ins += rows;
+    }
+
+    pub fn count_update(&mut self, reloid: Oid, rows: u64) {
+        self.get(reloid).tup_upd += rows;
+    }
+
+    pub fn count_delete(&mut self, reloid: Oid, rows: u64) {
+        self.get(reloid).tup_del += rows;
+    }
+
+    pub fn flush(&mut self, tabstats: &TabStats) {
+        if !self.tabs.is_empty() {
+            tabstats.merge(&self.tabs);
+            self.tabs.clear();
+        }
+        return;
+    }
+}
+
+#[cfg(test)]
+mod table_test {
+    use super::{TabStatPending, TabStats};
+    use crate::Oid;
+
+    #[test]
+    fn f() {
+        let reloid = Oid::new(16384).unwrap();
+        let tabstats = TabStats::default();
+        let mut pending = TabStatPending::default();
+        pending.count_seq_scan(reloid);
+        pending.count_read(reloid, 10);
+        pending.count_insert(reloid, 3);
+        pending.count_delete(reloid, 2);
+        assert!(tabstats.get(reloid).is_none());
+        pending.flush(&tabstats);
+        let tabstat = tabstats.get(reloid).unwrap();
+        assert_eq!(tabstat.counters.seq_scan, 1);
+        assert_eq!(tabstat.counters.tup_read, 10);
+        assert_eq!(tabstat.n_dead_tup, 2);
+        tabstats.report_vacuum(reloid);
+        let tabstat = tabstats.get(reloid).unwrap();
+        assert_eq!(tabstat.n_dead_tup, 0);
+        assert_eq!(tabstat.vacuum_count, 1);
+        assert!(tabstat.last_vacuum.is_some());
+        assert_eq!(tabstats.user_tables().len(), 1);
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering::Relaxed};
+use std::time::Instant;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WaitEvent {
+    None = 0,
+    ClientRead,
+    ClientWrite,
+}
+
+const WAIT_EVENT_NUM: usize = WaitEvent::ClientWrite as usize + 1;
+
+const WAIT_EVENTS: [WaitEvent; WAIT_EVENT_NUM] = [
+    WaitEvent::None,
+    WaitEvent::ClientRead,
+    WaitEvent::ClientWrite,
+];
+
+impl WaitEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            WaitEvent::None => "None",
+            WaitEvent::ClientRead => "ClientRead",
+            WaitEvent::ClientWrite => "ClientWrite",
+        }
+    }
+}
+
+// The wait event of one session.
+#[derive(Default)]
+pub struct WaitState {
+    cur: AtomicU8,
+    cnt: [AtomicU64; WAIT_EVENT_NUM],
+    time_us: [AtomicU64; WAIT_EVENT_NUM],
+}
+
+impl WaitState {
+    pub fn current(&self) -> WaitEvent {
+        WAIT_EVENTS[self.cur.load(Relaxed) as usize]
+    }
+
+    // Wait events can not be nested, the outer one will be overwritten.
+    pub fn start(&self, ev: WaitEvent) -> WaitGuard<'_> {
+        self.cur.store(ev as u8, Relaxed);
+        WaitGuard {
+            state: self,
+            ev,
+            start: Instant::now(),
+        }
+    }
+
+    // Return (event, count, total wait time in microseconds) of all events that have been waited.
+    pub fn snapshot(&self) -> Vec<(WaitEvent, u64, u64)> {
+        let mut ret = Vec::new();
+        for &ev in &WAIT_EVENTS[1..] {
+            let cnt = self.cnt[ev as usize].load(Relaxed);
+            if cnt > 0 {
+                ret.push((ev, cnt, self.time_us[ev as usize].load(Relaxed)));
+            }
+        }
+        return ret;
+    }
+}
+
+pub struct WaitGuard<'a> {
+    state: &'a WaitState,
+    ev: WaitEvent,
+    start: Instant,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        let idx = self.ev as usize;
+        let elapsed = self.start.elapsed().as_micros() as u64;
+        self.state.cur.store(WaitEvent::None as u8, Relaxed);
+        self.state.cnt[idx].fetch_add(1, Relaxed);
+        self.state.time_us[idx].fetch_add(elapsed, Relaxed);
+    }
+}
+
+// The number of times each wait event has been observed by the sampler.
+#[derive(Default)]
+pub struct WaitSamples {
+    samples: [AtomicU64; WAIT_EVENT_NUM],
+}
+
+impl WaitSamples {
+    pub fn add(&self, ev: WaitEvent) {
+        self.samples[ev as usize].fetch_add(1, Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Vec<(WaitEvent, u64)> {
+        WAIT_EVENTS
+            .iter()
+            .map(|&ev| (ev, self.samples[ev as usize].load(Relaxed)))
+            .filter(|&(_, v)| v > 0)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod wait_test {
+    use super::{WaitEvent, WaitSamples, WaitState};
+
+    #[test]
+    fn f() {
+        let state = WaitState::default();
+        assert_eq!(state.current(), WaitEvent::None);
+        {
+            let _guard = state.start(WaitEvent::ClientRead);
+            assert_eq!(state.current(), WaitEvent::ClientRead);
+        }
+        assert_eq!(state.current(), WaitEvent::None);
+        let snap = state.snapshot();
+        assert_eq!(snap.len(), 1);
+        assert_eq!(snap[0].0, WaitEvent::ClientRead);
+        assert_eq!(snap[0].1, 1);
+
+        let samples = WaitSamples::default();
+        samples.add(state.current());
+        samples.add(WaitEvent::ClientWrite);
+        assert_eq!(
+            samples.snapshot(),
+            vec![(WaitEvent::None, 1), (WaitEvent::ClientWrite, 1)]
+        );
+    }
+}
@@ -11,8 +11,13 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
+pub mod crash;
 pub mod err;
+pub mod lsn;
+pub mod mem;
 pub mod ser;
+pub mod tmpfile;
 
 pub type AttrNumber = std::num::NonZeroU16;
 pub type Xid = std::num::NonZeroU64;
+pub use lsn::Lsn;
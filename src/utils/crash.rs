@@ -0,0 +1,75 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::stat::Stat;
+use std::backtrace::Backtrace;
+use std::fmt::Write;
+use std::panic::PanicHookInfo;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+// We abort on panic, the panic hook is the last chance to leave something for post-mortem analysis.
+fn crash_report(stat: &Stat, info: &PanicHookInfo<'_>) -> String {
+    let mut report = String::new();
+    let thread = std::thread::current();
+    let _ = writeln!(
+        &mut report,
+        "panic: {}\nthread: {}",
+        info,
+        thread.name().unwrap_or("<unnamed>")
+    );
+    match stat.try_backends() {
+        None => {
+            let _ = writeln!(&mut report, "sessions: <locked>");
+        }
+        Some(backends) => {
+            let _ = writeln!(&mut report, "sessions: {}", backends.len());
+            for backend in backends {
+                let query = backend.query.try_lock();
+                let query = query.as_ref().map_or("<locked>", |v| v.as_str());
+                let _ = writeln!(
+                    &mut report,
+                    "  sessid={} wait_event={:?} query={:?}",
+                    backend.sessid,
+                    backend.wait.current(),
+                    query
+                );
+            }
+        }
+    }
+    let _ = writeln!(&mut report, "backtrace:\n{}", Backtrace::force_capture());
+    return report;
+}
+
+fn write_crash_report(report: &str) {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |v| v.as_secs());
+    // The working directory is the data directory, see crate::init().
+    let path = format!("kb_crash_{}_{}.txt", ts, std::process::id());
+    if let Err(err) = std::fs::write(&path, report) {
+        error!("write crash report failed. path={} err={}", path, err);
+    } else {
+        error!("write crash report. path={}", path);
+    }
+    return;
+}
+
+pub fn install_panic_hook(stat: &'static Stat, write_report: bool) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let report = crash_report(stat, info);
+        error!("server panic. {}", report);
+        if write_report {
+            write_crash_report(&report);
+        }
+        default_hook(info);
+    }));
+}
@@ -8,10 +8,32 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+// Where the error was raised, used to fill the F/L fields of ErrorResponse.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrLoc {
+    pub file: &'static str,
+    pub line: u32,
+}
+
 #[derive(Debug)]
 pub struct ErrCtx {
     pub code: &'static str,
     pub msg: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+    pub loc: Option<ErrLoc>,
+}
+
+impl ErrCtx {
+    pub fn detail<T: ToString>(mut self, detail: T) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    pub fn hint<T: ToString>(mut self, hint: T) -> Self {
+        self.hint = Some(hint.to_string());
+        self
+    }
 }
 
 // crate::on_error() has already output `code`,
@@ -22,26 +44,46 @@ impl std::fmt::Display for ErrCtx {
     }
 }
 
+pub fn errctx(err: &anyhow::Error) -> Option<&ErrCtx> {
+    err.downcast_ref::<ErrCtx>()
+}
+
 pub fn errcode(err: &anyhow::Error) -> &'static str {
-    if let Some(errctx) = err.downcast_ref::<ErrCtx>() {
+    if let Some(errctx) = errctx(err) {
         errctx.code
     } else {
         crate::protocol::ERRCODE_INTERNAL_ERROR
     }
 }
 
+#[macro_export]
+macro_rules! errloc {
+    () => {
+        $crate::utils::err::ErrLoc {
+            file: file!(),
+            line: line!(),
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! errctx {
     ($code:ident, $msg:literal $(,)?) => {
         $crate::utils::err::ErrCtx {
             code: $crate::protocol::$code,
             msg: $msg.to_string(),
+            detail: None,
+            hint: None,
+            loc: Some($crate::errloc!()),
         }
     };
     ($code:ident, $fmt:expr, $($arg:tt)*) => {
         $crate::utils::err::ErrCtx {
             code: $crate::protocol::$code,
             msg: format!($fmt, $($arg)*),
+            detail: None,
+            hint: None,
+            loc: Some($crate::errloc!()),
         }
     };
 }
@@ -79,3 +121,24 @@ macro_rules! kbensure {
         }
     };
 }
+
+#[cfg(test)]
+mod err_test {
+    use super::{errcode, errctx};
+    use crate::protocol::{ERRCODE_INTERNAL_ERROR, ERRCODE_PROTOCOL_VIOLATION};
+
+    #[test]
+    fn f() {
+        let err = anyhow::anyhow!("").context(
+            crate::errctx!(ERRCODE_PROTOCOL_VIOLATION, "invalid message")
+                .detail("d")
+                .hint("h"),
+        );
+        assert_eq!(errcode(&err), ERRCODE_PROTOCOL_VIOLATION);
+        let ctx = errctx(&err).unwrap();
+        assert_eq!(ctx.detail.as_deref(), Some("d"));
+        assert_eq!(ctx.hint.as_deref(), Some("h"));
+        assert_eq!(ctx.loc.unwrap().file, file!());
+        assert_eq!(errcode(&anyhow::anyhow!("x")), ERRCODE_INTERNAL_ERROR);
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::kbanyhow;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::num::NonZeroU64;
+use std::str::FromStr;
+
+// Lsn is never 0, so Option<Lsn> has the same size as Lsn.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Lsn(NonZeroU64);
+
+impl Lsn {
+    pub const fn new(val: u64) -> Option<Lsn> {
+        match NonZeroU64::new(val) {
+            None => None,
+            Some(v) => Some(Lsn(v)),
+        }
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0.get()
+    }
+
+    // The segment number of the wal file containing this lsn.
+    pub fn segment_of(self, segsize: u64) -> u64 {
+        self.get() / segsize
+    }
+
+    pub fn segment_offset(self, segsize: u64) -> u64 {
+        self.get() % segsize
+    }
+}
+
+impl std::ops::Add<usize> for Lsn {
+    type Output = Lsn;
+    fn add(self, rhs: usize) -> Lsn {
+        Lsn(self.0.checked_add(rhs as u64).expect("lsn overflow"))
+    }
+}
+
+impl std::ops::AddAssign<usize> for Lsn {
+    fn add_assign(&mut self, rhs: usize) {
+        *self = *self + rhs;
+    }
+}
+
+// The number of bytes between two lsns.
+impl std::ops::Sub for Lsn {
+    type Output = u64;
+    fn sub(self, rhs: Lsn) -> u64 {
+        self.get() - rhs.get()
+    }
+}
+
+// Same as PostgreSQL, %X/%08X.
+impl fmt::Display for Lsn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let v = self.get();
+        write!(f, "{:X}/{:08X}", v >> 32, v as u32)
+    }
+}
+
+impl fmt::Debug for Lsn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for Lsn {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Lsn> {
+        let invalid = || kbanyhow!(ERRCODE_INVALID_PARAMETER_VALUE, "invalid lsn. lsn={}", s);
+        let mut parts = s.splitn(2, '/');
+        let hi = parts.next().ok_or_else(invalid)?;
+        let lo = parts.next().ok_or_else(invalid)?;
+        // Same as pg_lsn_in, only hex digits are accepted, from_str_radix alone
+        // would accept a leading '+'.
+        let is_hex =
+            |v: &str| !v.is_empty() && v.len() <= 8 && v.bytes().all(|c| c.is_ascii_hexdigit());
+        if !is_hex(hi) || !is_hex(lo) {
+            return Err(invalid());
+        }
+        let hi = u32::from_str_radix(hi, 16).map_err(|_| invalid())?;
+        let lo = u32::from_str_radix(lo, 16).map_err(|_| invalid())?;
+        Lsn::new(((hi as u64) << 32) | lo as u64).ok_or_else(invalid)
+    }
+}
+
+impl Serialize for Lsn {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.get())
+    }
+}
+
+impl<'de> Deserialize<'de> for Lsn {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Lsn, D::Error> {
+        let v = u64::deserialize(deserializer)?;
+        Lsn::new(v).ok_or_else(|| de::Error::custom("lsn can not be 0"))
+    }
+}
+
+#[cfg(test)]
+mod lsn_test {
+    use super::Lsn;
+
+    #[test]
+    fn f() {
+        assert!(Lsn::new(0).is_none());
+        let lsn = Lsn::new(0x1_0000_0010).unwrap();
+        assert_eq!(lsn.to_string(), "1/00000010");
+        assert_eq!("1/00000010".parse::<Lsn>().unwrap(), lsn);
+        assert_eq!("1/10".parse::<Lsn>().unwrap(), lsn);
+        assert!("0/0".parse::<Lsn>().is_err());
+        assert!("10".parse::<Lsn>().is_err());
+        assert!("1/100000000".parse::<Lsn>().is_err());
+        assert!("+1/10".parse::<Lsn>().is_err());
+        assert!("1/+10".parse::<Lsn>().is_err());
+        assert!("1/ 10".parse::<Lsn>().is_err());
+        let next = lsn + 0x10;
+        assert_eq!(next - lsn, 0x10);
+        assert_eq!(next.segment_of(0x1_0000_0000), 1);
+        assert_eq!(next.segment_offset(0x1_0000_0000), 0x20);
+        assert_eq!(std::mem::size_of::<Option<Lsn>>(), 8);
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Memory accounting. The trackers form a tree: session -> query -> operator.
+// The session and query trackers have a hard limit, exceeding it is an error.
+// The operator tracker has a soft limit(work_mem), exceeding it means the operator
+// should spill to disk.
+use crate::guc::{self, GucState};
+use crate::kbbail;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::Arc;
+
+pub struct MemTracker {
+    name: &'static str,
+    used: AtomicUsize,
+    limit: usize, // 0 means unlimited.
+    parent: Option<Arc<MemTracker>>,
+}
+
+// mem_kb_preassign() rejects negative values, treat them as 0 anyway rather than
+// wrapping to a huge limit.
+fn kb2bytes(kb: i32) -> usize {
+    (kb.max(0) as usize).saturating_mul(1024)
+}
+
+impl MemTracker {
+    pub fn new(name: &'static str, limit: usize, parent: Option<Arc<MemTracker>>) -> MemTracker {
+        MemTracker {
+            name,
+            used: AtomicUsize::new(0),
+            limit,
+            parent,
+        }
+    }
+
+    pub fn new_session(gucstate: &GucState) -> Arc<MemTracker> {
+        let limit = kb2bytes(guc::get_int(gucstate, guc::SessionMemLimit));
+        Arc::new(MemTracker::new("session", limit, None))
+    }
+
+    pub fn new_query(gucstate: &GucState, sess: &Arc<MemTracker>) -> Arc<MemTracker> {
+        let limit = kb2bytes(guc::get_int(gucstate, guc::QueryMemLimit));
+        Arc::new(MemTracker::new("query", limit, Some(sess.clone())))
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Relaxed)
+    }
+
+    fn try_reserve(&self, bytes: usize) -> bool {
+        let used = self.used.fetch_add(bytes, Relaxed) + bytes;
+        if self.limit > 0 && used > self.limit {
+            self.used.fetch_sub(bytes, Relaxed);
+            return false;
+        }
+        return true;
+    }
+
+    pub fn reserve(&self, bytes: usize) -> anyhow::Result<()> {
+        if !self.try_reserve(bytes) {
+            kbbail!(
+                ERRCODE_OUT_OF_MEMORY,
+                "out of memory. tracker={} used={} request={} limit={}",
+                self.name,
+                self.used(),
+                bytes,
+                self.limit
+            );
+        }
+        if let Some(parent) = &self.parent {
+            if let Err(err) = parent.reserve(bytes) {
+                self.used.fetch_sub(bytes, Relaxed);
+                return Err(err);
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn release(&self, bytes: usize) {
+        debug_assert!(self.used() >= bytes);
+        self.used.fetch_sub(bytes, Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.release(bytes);
+        }
+        return;
+    }
+}
+
+// The memory budget of one operator, such as sort, hash join and aggregation.
+pub struct WorkMem {
+    query: Arc<MemTracker>,
+    budget: usize,
+    used: usize,
+}
+
+impl WorkMem {
+    pub fn new(gucstate: &GucState, query: &Arc<MemTracker>) -> WorkMem {
+        WorkMem {
+            query: query.clone(),
+            budget: kb2bytes(guc::get_int(gucstate, guc::WorkMem)),
+            used: 0,
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    // Return Ok(false) if the operator exceeds work_mem and should spill to disk,
+    // nothing is reserved in this case.
+    pub fn reserve(&mut self, bytes: usize) -> anyhow::Result<bool> {
+        if self.used + bytes > self.budget {
+            return Ok(false);
+        }
+        self.query.reserve(bytes)?;
+        self.used += bytes;
+        return Ok(true);
+    }
+
+    pub fn release(&mut self, bytes: usize) {
+        debug_assert!(self.used >= bytes);
+        self.query.release(bytes);
+        self.used -= bytes;
+    }
+
+    // Called after the operator has spilled its in-memory data to disk.
+    pub fn release_all(&mut self) {
+        self.query.release(self.used);
+        self.used = 0;
+    }
+}
+
+impl Drop for WorkMem {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
+
+#[cfg(test)]
+mod mem_test {
+    use super::{kb2bytes, MemTracker, WorkMem};
+    use crate::guc::{self, GucState};
+    use crate::protocol::ERRCODE_OUT_OF_MEMORY;
+    use crate::utils::err::errcode;
+
+    #[test]
+    fn f() {
+        let mut gucstate = GucState::default();
+        guc::set_int_guc(guc::WorkMem, 1, &mut gucstate);
+        guc::set_int_guc(guc::QueryMemLimit, 2, &mut gucstate);
+        guc::set_int_guc(guc::QueryMemLimit, -1, &mut gucstate);
+        assert_eq!(guc::get_int(&gucstate, guc::QueryMemLimit), 2);
+        assert_eq!(kb2bytes(-1), 0);
+        let sess = MemTracker::new_session(&gucstate);
+        let query = MemTracker::new_query(&gucstate, &sess);
+        let mut op1 = WorkMem::new(&gucstate, &query);
+        let mut op2 = WorkMem::new(&gucstate, &query);
+        assert!(op1.reserve(1024).unwrap());
+        assert!(!op1.reserve(1).unwrap());
+        assert!(op2.reserve(1000).unwrap());
+        assert_eq!(sess.used(), 2024);
+        let mut op3 = WorkMem::new(&gucstate, &query);
+        let err = op3.reserve(100).unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_OUT_OF_MEMORY);
+        assert_eq!(query.used(), 2024);
+        op1.release_all();
+        assert!(op3.reserve(100).unwrap());
+        drop(op2);
+        drop(op3);
+        assert_eq!(query.used(), 0);
+        assert_eq!(sess.used(), 0);
+    }
+}
@@ -0,0 +1,266 @@
+// Copyright 2021 <盏一 w@hidva.com>
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Temporary files used by sorts, hash spills and materializations.
+// A temporary file is removed when it is dropped, and all temporary files left by
+// the previous crash are removed at startup, see TempDirs::cleanup().
+use crate::guc::{self, GucState};
+use crate::{errctx, kbbail};
+use anyhow::Context;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+const DEFAULT_TEMP_DIR: &str = "kb_tmp";
+const TEMP_FILE_PREFIX: &str = "kbtmp";
+
+pub struct TempDirs {
+    dirs: Vec<PathBuf>,
+    next: AtomicUsize,
+    seq: AtomicU64,
+}
+
+impl TempDirs {
+    // temp_tablespaces is a comma-separated list of directories,
+    // which allows us to put temporary files on a dedicated disk.
+    pub fn new(gucstate: &GucState) -> TempDirs {
+        let mut dirs: Vec<PathBuf> = guc::get_str(gucstate, guc::TempTablespaces)
+            .split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        if dirs.is_empty() {
+            dirs.push(PathBuf::from(DEFAULT_TEMP_DIR));
+        }
+        TempDirs {
+            dirs,
+            next: AtomicUsize::new(0),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    // Called at startup, remove the temporary files left by the previous run.
+    pub fn cleanup(&self) -> anyhow::Result<()> {
+        for dir in &self.dirs {
+            std::fs::create_dir_all(dir).with_context(|| {
+                errctx!(ERRCODE_IO_ERROR, "create temp dir failed. dir={:?}", dir)
+            })?;
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                let is_temp = path
+                    .file_name()
+                    .and_then(|v| v.to_str())
+                    .map_or(false, |v| v.starts_with(TEMP_FILE_PREFIX));
+                if !is_temp {
+                    continue;
+                }
+                info!("remove temp file left by previous run. path={:?}", path);
+                if let Err(err) = std::fs::remove_file(&path) {
+                    warn!("remove temp file failed. path={:?} err={}", path, err);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn next_path(&self, sessid: u32) -> PathBuf {
+        let idx = self.next.fetch_add(1, Relaxed) % self.dirs.len();
+        let seq = self.seq.fetch_add(1, Relaxed);
+        self.dirs[idx].join(format!("{}_{}.{}", TEMP_FILE_PREFIX, sessid, seq))
+    }
+}
+
+// The temporary files of one session, used to enforce temp_file_limit.
+pub struct SessTempFiles {
+    dirs: &'static TempDirs,
+    sessid: u32,
+    limit: Option<u64>, // None means unlimited.
+    used: Arc<AtomicU64>,
+}
+
+impl SessTempFiles {
+    pub fn new(dirs: &'static TempDirs, sessid: u32, gucstate: &GucState) -> SessTempFiles {
+        let limit = guc::get_int(gucstate, guc::TempFileLimit);
+        SessTempFiles {
+            dirs,
+            sessid,
+            // Same as PostgreSQL, -1 means no limit and 0 means no temporary file
+            // can be written.
+            limit: if limit < 0 {
+                None
+            } else {
+                Some(limit as u64 * 1024)
+            },
+            used: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Relaxed)
+    }
+
+    pub fn create(&self) -> anyhow::Result<TempFile> {
+        let path = self.dirs.next_path(self.sessid);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| {
+                errctx!(
+                    ERRCODE_IO_ERROR,
+                    "could not create temporary file. path={:?}",
+                    path
+                )
+            })?;
+        return Ok(TempFile {
+            file,
+            path,
+            size: 0,
+            limit: self.limit,
+            used: self.used.clone(),
+        });
+    }
+}
+
+pub struct TempFile {
+    file: File,
+    path: PathBuf,
+    size: u64,
+    limit: Option<u64>,
+    used: Arc<AtomicU64>,
+}
+
+impl TempFile {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn grow(&mut self, newsize: u64) -> anyhow::Result<()> {
+        if newsize <= self.size {
+            return Ok(());
+        }
+        let delta = newsize - self.size;
+        let used = self.used.fetch_add(delta, Relaxed) + delta;
+        if let Some(limit) = self.limit {
+            if used > limit {
+                self.used.fetch_sub(delta, Relaxed);
+                kbbail!(
+                    ERRCODE_CONFIGURATION_LIMIT_EXCEEDED,
+                    "temporary file size exceeds temp_file_limit. limit={}kB",
+                    limit / 1024
+                );
+            }
+        }
+        self.size = newsize;
+        return Ok(());
+    }
+
+    pub fn write_at(&mut self, buf: &[u8], off: u64) -> anyhow::Result<()> {
+        self.grow(off + buf.len() as u64)?;
+        self.file.write_all_at(buf, off).with_context(|| {
+            errctx!(
+                ERRCODE_IO_ERROR,
+                "could not write to temporary file. path={:?}",
+                self.path
+            )
+        })?;
+        return Ok(());
+    }
+
+    pub fn append(&mut self, buf: &[u8]) -> anyhow::Result<u64> {
+        let off = self.size;
+        self.write_at(buf, off)?;
+        return Ok(off);
+    }
+
+    pub fn read_at(&self, buf: &mut [u8], off: u64) -> anyhow::Result<()> {
+        self.file.read_exact_at(buf, off).with_context(|| {
+            errctx!(
+                ERRCODE_IO_ERROR,
+                "could not read from temporary file. path={:?}",
+                self.path
+            )
+        })?;
+        return Ok(());
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        self.used.fetch_sub(self.size, Relaxed);
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!("remove temp file failed. path={:?} err={}", self.path, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tmpfile_test {
+    use super::{SessTempFiles, TempDirs};
+    use crate::make_static;
+    use crate::protocol::ERRCODE_CONFIGURATION_LIMIT_EXCEEDED;
+    use crate::utils::err::errcode;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicU64, AtomicUsize};
+    use std::sync::Arc;
+
+    #[test]
+    fn f() {
+        let dir: PathBuf = std::env::temp_dir().join(format!("kbtmptest{}", std::process::id()));
+        let dirs = make_static(TempDirs {
+            dirs: vec![dir.clone()],
+            next: AtomicUsize::new(0),
+            seq: AtomicU64::new(0),
+        });
+        dirs.cleanup().unwrap();
+        let sess = SessTempFiles {
+            dirs,
+            sessid: 1,
+            limit: Some(8),
+            used: Arc::new(AtomicU64::new(0)),
+        };
+        let mut f1 = sess.create().unwrap();
+        assert_eq!(f1.append(b"hello").unwrap(), 0);
+        let mut buf = [0u8; 5];
+        f1.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"hello");
+        let mut f2 = sess.create().unwrap();
+        let err = f2.append(b"world").unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_CONFIGURATION_LIMIT_EXCEEDED);
+        drop(f1);
+        assert_eq!(sess.used(), 0);
+        f2.append(b"world").unwrap();
+        drop(f2);
+
+        let nolimit = SessTempFiles {
+            limit: None,
+            ..sess
+        };
+        let mut f3 = nolimit.create().unwrap();
+        f3.append(&[0u8; 64]).unwrap();
+        drop(f3);
+        let zero = SessTempFiles {
+            limit: Some(0),
+            ..nolimit
+        };
+        let mut f4 = zero.create().unwrap();
+        let err = f4.append(b"x").unwrap_err();
+        assert_eq!(errcode(&err), ERRCODE_CONFIGURATION_LIMIT_EXCEEDED);
+        drop(f4);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}